@@ -1,47 +1,254 @@
-use crate::batcher::BatchSender;
+use crate::batcher::{BatchSender, RequestOptions};
+use crate::circuit::CircuitBreaker;
+use crate::config::AppConfig;
 use crate::error::ProxyError;
-use actix_web::{HttpResponse, Responder, get, post, web};
+use crate::metrics::{Metrics, write_family};
+use crate::queue::Priority;
+use crate::tenant::TenantId;
+use crate::upstream::Upstreams;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{HttpRequest, HttpResponse, Responder, get, post, web};
 use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 
+/// Header carrying the client's time budget for the whole request, in milliseconds.
+pub(crate) const REQUEST_TIMEOUT_HEADER: &str = "x-request-timeout-ms";
+/// Header selecting the scheduling class, `high` or `low`.
+pub(crate) const PRIORITY_HEADER: &str = "x-priority";
+/// Header naming the tenant for fair queuing; without it the API key is used.
+pub(crate) const TENANT_HEADER: &str = "x-tenant";
+
+/// Per-request options that may also be given in the JSON body, where they win over the headers.
+#[derive(Deserialize, Default)]
+pub(crate) struct BodyOptions {
+    /// Same as the `X-Request-Timeout-Ms` header.
+    timeout_ms: Option<u64>,
+    /// Same as the `X-Priority` header.
+    priority: Option<Priority>,
+}
+
+/// Reads per-request options from the body, falling back to the headers.
+pub(crate) fn request_options(req: &HttpRequest, body: &BodyOptions) -> Result<RequestOptions, ProxyError> {
+    let timeout_ms = match body.timeout_ms {
+        Some(ms) => Some(ms),
+        None => header(req, REQUEST_TIMEOUT_HEADER)?,
+    };
+    let priority = match body.priority {
+        Some(p) => p,
+        None => header(req, PRIORITY_HEADER)?.unwrap_or_default(),
+    };
+
+    Ok(RequestOptions {
+        deadline: timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms)),
+        priority,
+        tenant: tenant(req),
+    })
+}
+
+/// `X-Tenant`, or else the bearer token of the `Authorization` header.
+fn tenant(req: &HttpRequest) -> TenantId {
+    let headers = req.headers();
+    let tenant = match headers.get(TENANT_HEADER) {
+        Some(v) => v.to_str().ok(),
+        None => headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer ")),
+    };
+
+    tenant.map(str::trim).filter(|t| !t.is_empty()).map(Arc::from)
+}
+
+fn header<T: FromStr>(req: &HttpRequest, name: &str) -> Result<Option<T>, ProxyError> {
+    req.headers()
+        .get(name)
+        .map(|v| {
+            v.to_str()
+                .ok()
+                .and_then(|s| s.trim().parse().ok())
+                .ok_or_else(|| ProxyError::InvalidRequest(format!("invalid {name} header")))
+        })
+        .transpose()
+}
+
+/// Reports the upstream circuit state, see [`CircuitBreaker`].
+pub const CIRCUIT_STATE_HEADER: &str = "x-circuit-state";
+
+/// Liveness only, see `/ready` for whether the proxy can serve. The upstream circuit state, when a breaker is configured, is in `X-Circuit-State`.
 #[get("/health")]
-async fn health() -> impl Responder {
-    HttpResponse::Ok().body("ok")
+async fn health(breaker: Option<web::Data<CircuitBreaker>>) -> impl Responder {
+    let mut res = HttpResponse::Ok();
+    if let Some(breaker) = breaker {
+        res.insert_header((CIRCUIT_STATE_HEADER, breaker.state().to_string()));
+    }
+
+    res.body("ok")
+}
+
+/// When `/ready` fails.
+#[derive(Clone, Copy, Debug)]
+pub struct Readiness {
+    /// Items queued in either lane above which the proxy is not ready.
+    pub queue_high_water: usize,
+    /// How recently some replica must have answered; `None` skips the check.
+    pub upstream_max_age: Option<Duration>,
+}
+
+impl Readiness {
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        Self {
+            queue_high_water: (cfg.queue_cap as f64 * cfg.ready_queue_high_water) as usize,
+            // Without health probes only traffic shows that a replica answers, so an idle pod would never be ready
+            upstream_max_age: match (cfg.health_check_interval_ms, cfg.ready_upstream_max_age_ms) {
+                (0, _) | (_, 0) => None,
+                (_, ms) => Some(Duration::from_millis(ms)),
+            },
+        }
+    }
+}
+
+/// Readiness. Fails with 503 and the reasons, one per line, while shutting down, while the batcher
+/// is gone, the queue is above its high-water mark or no replica answered recently.
+#[get("/ready")]
+async fn ready(
+    readiness: web::Data<Readiness>,
+    upstream: web::Data<BatchSender>,
+    upstreams: web::Data<Upstreams>,
+    counters: web::Data<Metrics>,
+) -> impl Responder {
+    let mut reasons = Vec::new();
+    if upstream.is_draining() {
+        reasons.push("shutting down".to_string());
+    }
+    if upstream.is_closed() {
+        reasons.push("batcher exited".to_string());
+    }
+
+    let queued = queued(&upstream, &counters, Priority::High).max(queued(&upstream, &counters, Priority::Low));
+    if queued > readiness.queue_high_water {
+        reasons.push(format!(
+            "queue above high-water mark ({queued} > {})",
+            readiness.queue_high_water
+        ));
+    }
+
+    if let Some(max_age) = readiness.upstream_max_age
+        && !upstreams.answered_within(max_age)
+    {
+        reasons.push(format!("no upstream answered in the last {}ms", max_age.as_millis()));
+    }
+
+    if reasons.is_empty() {
+        return HttpResponse::Ok().body("ready");
+    }
+
+    HttpResponse::ServiceUnavailable().body(reasons.join("\n"))
+}
+
+/// Items of `priority` waiting for a batch: still in the channel or held by the batcher.
+fn queued(upstream: &BatchSender, counters: &Metrics, priority: Priority) -> usize {
+    upstream.queued(priority) + counters.held(priority)
+}
+
+/// Prometheus metrics in text format.
+#[get("/metrics")]
+async fn metrics(
+    metrics: web::Data<Metrics>,
+    upstream: web::Data<BatchSender>,
+    upstreams: web::Data<Upstreams>,
+) -> impl Responder {
+    let mut out = String::new();
+    metrics.render(&mut out);
+
+    write_family(
+        &mut out,
+        "queue_depth",
+        "gauge",
+        "Items waiting for a batch, in the channel or held by the batcher, by priority lane.",
+        [
+            (
+                Some(("lane", "high")),
+                queued(&upstream, &metrics, Priority::High) as f64,
+            ),
+            (Some(("lane", "low")), queued(&upstream, &metrics, Priority::Low) as f64),
+        ],
+    );
+
+    let replicas = upstreams.replicas();
+    write_family(
+        &mut out,
+        "upstream_in_flight",
+        "gauge",
+        "Concurrency permits in use, by replica.",
+        replicas
+            .iter()
+            .map(|r| (Some(("upstream", r.url.as_str())), r.limiter.in_flight() as f64)),
+    );
+    write_family(
+        &mut out,
+        "upstream_concurrency_limit",
+        "gauge",
+        "Concurrency permits available, by replica.",
+        replicas
+            .iter()
+            .map(|r| (Some(("upstream", r.url.as_str())), r.limiter.limit() as f64)),
+    );
+
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(out)
+}
+
+/// Either a single text or a list of texts.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum EmbedInput {
+    Single(String),
+    Batch(Vec<String>),
 }
 
 #[derive(Deserialize)]
 struct EmbedReq {
-    input: String,
+    input: EmbedInput,
+    #[serde(flatten)]
+    opts: BodyOptions,
 }
 
+/// A single `input` string answers with `{ "embedding": [...] }`, an array answers
+/// with `{ "embeddings": [[...], ...] }` in the same order as the inputs.
 #[post("/embed")]
-async fn embed(upstream: web::Data<BatchSender>, body: web::Json<EmbedReq>) -> Result<impl Responder, ProxyError> {
-    let embedding = upstream.request(body.into_inner().input).await?;
+async fn embed(
+    req: HttpRequest,
+    upstream: web::Data<BatchSender>,
+    body: web::Json<EmbedReq>,
+) -> Result<impl Responder, ProxyError> {
+    let EmbedReq { input, opts } = body.into_inner();
+    let opts = request_options(&req, &opts)?;
+
+    match input {
+        EmbedInput::Single(input) => {
+            let embedding = upstream.request(input, &opts).await?;
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({ "embedding": embedding })))
+            Ok(HttpResponse::Ok().json(serde_json::json!({ "embedding": embedding })))
+        }
+        EmbedInput::Batch(inputs) => {
+            let embeddings = upstream.request_many(inputs, &opts).await?;
+
+            Ok(HttpResponse::Ok().json(serde_json::json!({ "embeddings": embeddings })))
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::AppConfig;
     use crate::batcher::{BatchItem, Batcher};
+    use crate::metrics::Metrics;
+    use crate::upstream::Upstreams;
     use actix_web::{App, test};
-    use std::sync::Arc;
     use tokio::sync::mpsc;
 
-    // Helper: build a BatchSender that always returns a fixed embedding
-    async fn test_sender_with_embedding(emb: Vec<f32>) -> BatchSender {
-        let (tx, mut rx) = mpsc::channel::<BatchItem>(16);
-
-        // Background task that replies to every BatchItem
-        tokio::spawn(async move {
-            while let Some(item) = rx.recv().await {
-                let _ = item.resp.send(Ok(emb.clone()));
-            }
-        });
-        BatchSender::new(tx)
-    }
-
     #[actix_web::test]
     async fn health_ok() {
         let app = test::init_service(App::new().service(health)).await;
@@ -54,9 +261,112 @@ mod tests {
         assert_eq!(body, "ok");
     }
 
+    #[actix_web::test]
+    async fn health_reports_circuit_state() {
+        let breaker = Arc::new(CircuitBreaker::new(Default::default()));
+        let app = test::init_service(App::new().app_data(web::Data::from(breaker)).service(health)).await;
+        let req = test::TestRequest::get().uri("/health").to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(CIRCUIT_STATE_HEADER).unwrap(), "closed");
+    }
+
+    #[actix_web::test]
+    async fn ready_fails_while_the_batcher_is_gone_or_the_upstream_silent() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(16);
+        let upstreams = Arc::new(Upstreams::new(
+            &["http://tei:80".to_string()],
+            crate::limiter::ConcurrencyLimits::fixed(1),
+            Default::default(),
+        ));
+        let readiness = Readiness {
+            queue_high_water: 8,
+            upstream_max_age: Some(Duration::from_secs(60)),
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(readiness))
+                .app_data(web::Data::new(BatchSender::new(tx)))
+                .app_data(web::Data::from(upstreams.clone()))
+                .app_data(web::Data::new(Metrics::default()))
+                .service(ready),
+        )
+        .await;
+        let get = || test::TestRequest::get().uri("/ready").to_request();
+
+        // No replica has answered yet
+        let resp = test::call_service(&app, get()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+
+        upstreams.acquire().await.unwrap().observe(Duration::from_millis(5));
+        let resp = test::call_service(&app, get()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        drop(rx);
+        let body = test::call_and_read_body(&app, get()).await;
+        assert_eq!(body, "batcher exited");
+    }
+
+    #[actix_web::test]
+    async fn readiness_skips_the_upstream_check_without_health_probes() {
+        let probed = AppConfig {
+            health_check_interval_ms: 5000,
+            ready_upstream_max_age_ms: 15000,
+            ..Default::default()
+        };
+        assert_eq!(
+            Readiness::from_config(&probed).upstream_max_age,
+            Some(Duration::from_secs(15))
+        );
+
+        let unprobed = AppConfig {
+            health_check_interval_ms: 0,
+            ..probed
+        };
+        assert_eq!(Readiness::from_config(&unprobed).upstream_max_age, None);
+    }
+
+    #[actix_web::test]
+    async fn metrics_reports_queue_depth_and_upstream_permits() {
+        let (tx, _rx) = mpsc::channel::<BatchItem>(16);
+        let (queued, _waiter) = BatchItem::test("queued", 1);
+        tx.send(queued).await.unwrap();
+        let sender = BatchSender::new(tx);
+        let upstreams = Arc::new(Upstreams::new(
+            &["http://tei:80".to_string()],
+            crate::limiter::ConcurrencyLimits::fixed(4),
+            Default::default(),
+        ));
+        let _lease = upstreams.acquire().await.unwrap();
+        let held = Metrics::default();
+        held.set_held(Priority::Low, 2);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(sender))
+                .app_data(web::Data::from(upstreams))
+                .app_data(web::Data::new(held))
+                .service(metrics),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+
+        assert!(body.contains("abp_queue_depth{lane=\"low\"} 3\n"), "{body}");
+        assert!(
+            body.contains("abp_upstream_in_flight{upstream=\"http://tei:80\"} 1\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("abp_upstream_concurrency_limit{upstream=\"http://tei:80\"} 4\n"),
+            "{body}"
+        );
+        assert!(body.contains("# TYPE abp_queue_wait_seconds histogram\n"), "{body}");
+    }
+
     #[actix_web::test]
     async fn embed_ok() {
-        let sender = test_sender_with_embedding(vec![1.0, 2.0, 3.5]).await;
+        let sender = BatchSender::answering(vec![1.0, 2.0, 3.5]);
         let app = test::init_service(App::new().app_data(web::Data::new(sender)).service(embed)).await;
         let req = test::TestRequest::post()
             .uri("/embed")
@@ -70,13 +380,50 @@ mod tests {
         assert_eq!(body["embedding"], serde_json::json!([1.0, 2.0, 3.5]));
     }
 
+    #[actix_web::test]
+    async fn embed_many_preserves_order_across_flushes() {
+        // Replies in batches of two, answering with the input length, so a
+        // five-element request is split over three flushes.
+        let (tx, mut rx) = mpsc::channel::<BatchItem>(16);
+        tokio::spawn(async move {
+            loop {
+                let mut batch = Vec::new();
+                if rx.recv_many(&mut batch, 2).await == 0 {
+                    break;
+                }
+                for item in batch {
+                    let _ = item.resp.send(Ok(vec![item.input.len() as f32]));
+                }
+            }
+        });
+        let sender = BatchSender::new(tx);
+        let app = test::init_service(App::new().app_data(web::Data::new(sender)).service(embed)).await;
+        let req = test::TestRequest::post()
+            .uri("/embed")
+            .set_json(serde_json::json!({ "input": ["a", "bbb", "cc", "dddd", "eeeee"] }))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body["embeddings"],
+            serde_json::json!([[1.0], [3.0], [2.0], [4.0], [5.0]])
+        );
+    }
+
     #[actix_web::test]
     async fn embed_upstream_ok() {
-        let cfg = AppConfig::default();
+        let cfg = AppConfig {
+            tei_urls: vec![std::env::var("TEI_URL").unwrap_or_else(|_| "http://tei:80".into())],
+            ..Default::default()
+        };
         let (tx, rx) = mpsc::channel::<BatchItem>(cfg.queue_cap);
         let upstream = Arc::new(BatchSender::new(tx));
 
-        Batcher::new(&cfg, rx).run(); // run batcher
+        let upstreams = Arc::new(Upstreams::from_config(&cfg));
+        Batcher::new(&cfg, rx, upstreams, Arc::new(Metrics::default())).run(); // run batcher
 
         let app = test::init_service(App::new().app_data(web::Data::from(upstream.clone())).service(embed)).await;
         let req = test::TestRequest::post()
@@ -93,6 +440,76 @@ mod tests {
         assert!(emb.iter().all(|v| v.as_f64().is_some()), "elements should be numbers");
     }
 
+    #[actix_web::test]
+    async fn embed_429_when_queue_stays_full() {
+        // Nobody drains the channel, so the second item can never be enqueued
+        let (tx, _rx) = mpsc::channel::<BatchItem>(1);
+        let sender = BatchSender::new(tx).with_enqueue_timeout(Duration::from_millis(20));
+        let app = test::init_service(App::new().app_data(web::Data::new(sender)).service(embed)).await;
+        let req = test::TestRequest::post()
+            .uri("/embed")
+            .set_json(serde_json::json!({ "input": ["first", "second"] }))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+    }
+
+    #[actix_web::test]
+    async fn embed_504_when_request_deadline_passes() {
+        // Items are accepted but never answered
+        let (tx, mut rx) = mpsc::channel::<BatchItem>(4);
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Some(item) = rx.recv().await {
+                held.push(item);
+            }
+        });
+        let sender = BatchSender::new(tx);
+        let app = test::init_service(App::new().app_data(web::Data::new(sender)).service(embed)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/embed")
+            .insert_header((REQUEST_TIMEOUT_HEADER, "20"))
+            .set_json(serde_json::json!({ "input": "hello" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::GATEWAY_TIMEOUT);
+
+        let req = test::TestRequest::post()
+            .uri("/embed")
+            .set_json(serde_json::json!({ "input": ["a", "b"], "timeout_ms": 20 }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[actix_web::test]
+    async fn embed_identifies_tenant_by_header_or_api_key() {
+        let (tx, mut rx) = mpsc::channel::<BatchItem>(4);
+        tokio::spawn(async move {
+            while let Some(item) = rx.recv().await {
+                let tenant = item.tenant.as_deref().unwrap_or("-").to_string();
+                let _ = item.resp.send(Ok(vec![tenant.len() as f32]));
+            }
+        });
+        let app = test::init_service(App::new().app_data(web::Data::new(BatchSender::new(tx))).service(embed)).await;
+
+        for (header, value, len) in [
+            (TENANT_HEADER, "search", 6.0),
+            ("authorization", "Bearer sk-abcdefgh", 11.0),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/embed")
+                .insert_header((header, value))
+                .set_json(serde_json::json!({ "input": "hello" }))
+                .to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body["embedding"], serde_json::json!([len]));
+        }
+    }
+
     #[actix_web::test]
     async fn embed_503_when_batcher_unavailable() {
         // Create a sender and immediately drop the receiver to simulate crash/stop
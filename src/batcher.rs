@@ -1,226 +1,1114 @@
-use crate::AppConfig;
+use crate::cache::EmbeddingCache;
+use crate::circuit::{Call, CircuitBreaker};
+use crate::config::AppConfig;
 use crate::error::ProxyError;
+use crate::inflight::InFlight;
+use crate::metrics::Metrics;
+use crate::queue::{BatchMode, BatchQueue, Priority, QueueLimits};
+use crate::retry::RetryPolicy;
+use crate::shutdown::Shutdown;
+use crate::telemetry;
+use crate::tenant::{TenantId, Tenants};
+use crate::tokens::{CharEstimator, TokenEstimator};
+use crate::upstream::{Lease, Upstreams};
+use crate::wait::AdaptiveWait;
 use reqwest::Client;
+use reqwest::header::HeaderMap;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::task::Poll;
 use std::{sync::Arc, time::Duration};
-use tokio::sync::mpsc::error::TryRecvError;
-use tokio::sync::{Semaphore, mpsc, oneshot};
+use tokio::sync::mpsc::error::{SendTimeoutError, TryRecvError};
+use tokio::sync::{mpsc, oneshot};
 use tokio::time::Instant;
+use tracing::{Instrument, Span};
+
+/// The accumulator loop may panic this many times within `RESTART_WINDOW` before the batcher gives up.
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+/// Pause before the first restart in the window, doubled for every further one.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
 
 pub struct BatchItem {
     pub input: String,
+    /// Estimated token count of `input`.
+    pub tokens: usize,
+    /// The caller's deadline; past it, the item is rejected instead of sent upstream.
+    pub deadline: Option<Instant>,
+    /// When the item was handed to the batcher, for the queue wait metric.
+    pub enqueued: Instant,
+    /// Covers the item's wait in the queue; the flush that takes it links to it.
+    pub span: Span,
+    pub priority: Priority,
+    pub tenant: TenantId,
     pub resp: oneshot::Sender<Result<Vec<f32>, ProxyError>>,
 }
 
+impl BatchItem {
+    /// The caller stopped waiting (e.g. the HTTP client disconnected), so the result would be discarded.
+    pub fn is_cancelled(&self) -> bool {
+        self.resp.is_closed()
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|d| now >= d)
+    }
+
+    /// A low-priority item without deadline or tenant, and the receiver its answer goes to.
+    #[cfg(test)]
+    pub(crate) fn test(input: &str, tokens: usize) -> (Self, oneshot::Receiver<Result<Vec<f32>, ProxyError>>) {
+        let (resp, rx) = oneshot::channel();
+        let item = Self {
+            input: input.into(),
+            tokens,
+            deadline: None,
+            enqueued: Instant::now(),
+            span: Span::none(),
+            priority: Priority::Low,
+            tenant: None,
+            resp,
+        };
+        (item, rx)
+    }
+}
+
+/// Per-request options supplied by the client.
+#[derive(Clone, Debug, Default)]
+pub struct RequestOptions {
+    pub deadline: Option<Instant>,
+    pub priority: Priority,
+    pub tenant: TenantId,
+}
+
+/// Returns `item` if it is still worth sending upstream. Cancelled items are dropped and
+/// counted; expired ones are answered with `ProxyError::DeadlineExceeded`.
+pub(crate) fn screen(item: BatchItem, now: Instant, metrics: &Metrics) -> Option<BatchItem> {
+    if item.is_cancelled() {
+        metrics.add_cancelled(1);
+        return None;
+    }
+
+    if item.is_expired(now) {
+        metrics.add_expired(1);
+        let _ = item.resp.send(Err(ProxyError::DeadlineExceeded));
+        return None;
+    }
+
+    Some(item)
+}
+
+/// Screens every item of `batch`, see [`screen`].
+fn prune(batch: &mut Vec<BatchItem>, metrics: &Metrics) {
+    let now = Instant::now();
+    *batch = std::mem::take(batch)
+        .into_iter()
+        .filter_map(|item| screen(item, now, metrics))
+        .collect();
+}
+
+/// Runs `fut` until `deadline`, if any. On expiry the future is dropped together with its
+/// response receivers, so the batcher sees the pending items as cancelled.
+async fn within_deadline<T>(
+    deadline: Option<Instant>,
+    fut: impl Future<Output = Result<T, ProxyError>>,
+) -> Result<T, ProxyError> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut)
+            .await
+            .map_err(|_| ProxyError::DeadlineExceeded)?,
+        None => fut.await,
+    }
+}
+
+/// Runs `fut` to completion, returning the payload instead of unwinding if it panics.
+async fn catch_unwind<T>(fut: impl Future<Output = T>) -> Result<T, Box<dyn Any + Send>> {
+    let mut fut = std::pin::pin!(fut);
+    std::future::poll_fn(
+        |cx| match panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(cx))) {
+            Ok(Poll::Ready(out)) => Poll::Ready(Ok(out)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(panic)),
+        },
+    )
+    .await
+}
+
+/// Pause before the `n`th restart within `RESTART_WINDOW`.
+fn restart_delay(n: usize) -> Duration {
+    RESTART_DELAY * 2u32.pow(n.saturating_sub(1) as u32)
+}
+
+/// The message a panic was raised with, if it was a string.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
 /// Sends items to the batcher.
 pub struct BatchSender {
     tx: mpsc::Sender<BatchItem>,
+    /// Separate channel for `Priority::High`, so interactive requests do not queue behind bulk ones.
+    tx_high: Option<mpsc::Sender<BatchItem>>,
+    estimator: Arc<dyn TokenEstimator>,
+    /// How long to wait for room in a full queue before rejecting; `None` waits indefinitely.
+    enqueue_timeout: Option<Duration>,
+    tenants: Arc<Tenants>,
+    cache: Option<Arc<EmbeddingCache>>,
+    shutdown: Option<Arc<Shutdown>>,
 }
 
 impl BatchSender {
     pub fn new(tx: mpsc::Sender<BatchItem>) -> Self {
-        Self { tx }
+        Self {
+            tx,
+            tx_high: None,
+            estimator: Arc::new(CharEstimator::default()),
+            enqueue_timeout: None,
+            tenants: Arc::new(Tenants::default()),
+            cache: None,
+            shutdown: None,
+        }
+    }
+
+    /// A sender whose requests are all answered with `embedding` right away, without a batcher.
+    #[cfg(test)]
+    pub(crate) fn answering(embedding: Vec<f32>) -> Self {
+        let (tx, mut rx) = mpsc::channel::<BatchItem>(16);
+        tokio::spawn(async move {
+            while let Some(item) = rx.recv().await {
+                let _ = item.resp.send(Ok(embedding.clone()));
+            }
+        });
+        Self::new(tx)
+    }
+
+    /// Turns new requests away with `ProxyError::ServiceShutdown` once `shutdown` is triggered.
+    pub fn with_shutdown(mut self, shutdown: Arc<Shutdown>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Answers repeated inputs from `cache` without queueing them. `None` disables caching.
+    pub fn with_cache(mut self, cache: Option<Arc<EmbeddingCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Enforces the configured per-tenant queue depth limits.
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    /// Rejects requests with `ProxyError::QueueFull` when the queue stays full for `timeout`.
+    pub fn with_enqueue_timeout(mut self, timeout: Duration) -> Self {
+        self.enqueue_timeout = Some(timeout);
+        self
+    }
+
+    /// Routes high-priority requests through their own channel, see [`Batcher::with_priority_lane`].
+    pub fn with_priority_lane(mut self, tx_high: mpsc::Sender<BatchItem>) -> Self {
+        self.tx_high = Some(tx_high);
+        self
+    }
+
+    /// Replaces the default character-based token estimator.
+    pub fn with_estimator(mut self, estimator: Arc<dyn TokenEstimator>) -> Self {
+        self.estimator = estimator;
+        self
+    }
+
+    pub fn estimate_tokens(&self, input: &str) -> usize {
+        self.estimator.estimate(input)
     }
 
     /// Enqueue and await result
-    pub async fn request(&self, input: String) -> Result<Vec<f32>, ProxyError> {
+    pub async fn request(&self, input: String, opts: &RequestOptions) -> Result<Vec<f32>, ProxyError> {
+        if let Some(embedding) = self.cached(&input).await {
+            return Ok(embedding);
+        }
+
+        let _permit = self.tenants.admit(&opts.tenant, 1)?;
+
+        within_deadline(opts.deadline, async {
+            let rx_resp = self.enqueue(input, opts).await?;
+
+            rx_resp.await?
+        })
+        .await
+    }
+
+    /// Enqueue every input as its own item and await all results in input order.
+    /// Items may end up in different upstream flushes; each one carries its own
+    /// response channel, so ordering is preserved regardless of how they are batched.
+    /// Cached inputs are answered directly and only the rest are enqueued.
+    pub async fn request_many(&self, inputs: Vec<String>, opts: &RequestOptions) -> Result<Vec<Vec<f32>>, ProxyError> {
+        let mut embeddings = Vec::with_capacity(inputs.len());
+        for input in &inputs {
+            embeddings.push(self.cached(input).await);
+        }
+        let misses = embeddings.iter().filter(|e| e.is_none()).count();
+        let _permit = self.tenants.admit(&opts.tenant, misses)?;
+
+        within_deadline(opts.deadline, async move {
+            let mut pending = Vec::with_capacity(misses);
+            for (input, cached) in inputs.into_iter().zip(&embeddings) {
+                if cached.is_none() {
+                    pending.push(self.enqueue(input, opts).await?);
+                }
+            }
+
+            let slots = embeddings.iter_mut().filter(|e| e.is_none());
+            for (slot, rx_resp) in slots.zip(pending) {
+                *slot = Some(rx_resp.await??);
+            }
+
+            Ok(embeddings.into_iter().flatten().collect())
+        })
+        .await
+    }
+
+    /// The batcher has exited and no request can be served anymore.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// A graceful shutdown is under way and new requests are turned away.
+    pub fn is_draining(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(|s| s.is_draining())
+    }
+
+    /// Items waiting in the channel of `priority` for the batcher to pick them up. Without a
+    /// priority lane, high-priority items are counted as `Priority::Low`.
+    pub fn queued(&self, priority: Priority) -> usize {
+        let tx = match (priority, &self.tx_high) {
+            (Priority::High, Some(tx_high)) => tx_high,
+            (Priority::High, None) => return 0,
+            (Priority::Low, _) => &self.tx,
+        };
+
+        tx.max_capacity() - tx.capacity()
+    }
+
+    async fn cached(&self, input: &str) -> Option<Vec<f32>> {
+        self.cache.as_ref()?.get(input).await
+    }
+
+    async fn enqueue(
+        &self,
+        input: String,
+        opts: &RequestOptions,
+    ) -> Result<oneshot::Receiver<Result<Vec<f32>, ProxyError>>, ProxyError> {
+        if self.is_draining() {
+            return Err(ProxyError::ServiceShutdown);
+        }
+
         let (tx_resp, rx_resp) = oneshot::channel();
-        let item = BatchItem { input, resp: tx_resp };
-        self.tx.send(item).await.map_err(|_| ProxyError::BatcherUnavailable)?;
+        let tokens = self.estimate_tokens(&input);
+        let item = BatchItem {
+            input,
+            tokens,
+            deadline: opts.deadline,
+            enqueued: Instant::now(),
+            span: tracing::info_span!("queued", tokens, priority = ?opts.priority),
+            priority: opts.priority,
+            tenant: opts.tenant.clone(),
+            resp: tx_resp,
+        };
+        let tx = match (opts.priority, &self.tx_high) {
+            (Priority::High, Some(tx_high)) => tx_high,
+            _ => &self.tx,
+        };
 
-        rx_resp.await?
+        match self.enqueue_timeout {
+            Some(timeout) => tx.send_timeout(item, timeout).await.map_err(|e| match e {
+                SendTimeoutError::Timeout(_) => {
+                    tracing::warn!(timeout_ms = timeout.as_millis() as u64, "enqueue timed out, queue full");
+                    ProxyError::QueueFull {
+                        retry_after: timeout.as_secs().max(1),
+                    }
+                }
+                SendTimeoutError::Closed(_) => ProxyError::BatcherUnavailable,
+            })?,
+            None => tx.send(item).await.map_err(|_| ProxyError::BatcherUnavailable)?,
+        }
+
+        Ok(rx_resp)
     }
 }
 
 /// Receives, batches, and sends items to the upstream TEI service.
 pub struct Batcher {
     rx: mpsc::Receiver<BatchItem>,
+    /// High-priority channel, read in turn with `rx`.
+    rx_high: Option<mpsc::Receiver<BatchItem>>,
+    /// Whether `rx_high` goes first on the next non-blocking read.
+    high_turn: bool,
     client: Client,
-    tei_url: String,
-    max_wait_time: Duration,
-    max_batch_size: usize,
-    /// Limits number of concurrent requests to the TEI.
-    inflight: Arc<Semaphore>,
+    /// TEI replicas, each with its own concurrency limiter.
+    upstreams: Arc<Upstreams>,
+    /// Sent to TEI with every flush, see `AppConfig::normalize`.
+    normalize: bool,
+    /// Items pulled from the channel and waiting to be flushed.
+    queue: BatchQueue,
+    /// Inputs owned by running flushes; identical items are coalesced into them.
+    in_flight: Arc<InFlight>,
+    cache: Option<Arc<EmbeddingCache>>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    retry: Option<Arc<RetryPolicy>>,
+    /// Picks `max_wait` per batch from the observed load; `None` keeps it fixed.
+    wait: Option<AdaptiveWait>,
+    /// Once triggered, held items are flushed right away and running flushes are tracked.
+    shutdown: Option<Arc<Shutdown>>,
+    metrics: Arc<Metrics>,
 }
 
 impl Batcher {
-    pub fn new(cfg: &AppConfig, rx: mpsc::Receiver<BatchItem>) -> Self {
+    pub fn new(
+        cfg: &AppConfig,
+        rx: mpsc::Receiver<BatchItem>,
+        upstreams: Arc<Upstreams>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         let client = Client::builder()
             .pool_max_idle_per_host(256)
             .pool_idle_timeout(Duration::from_secs(30))
             .tcp_nodelay(true)
             .http1_only()
+            .timeout(Duration::from_millis(cfg.upstream_timeout_ms))
+            .connect_timeout(Duration::from_millis(cfg.upstream_connect_timeout_ms))
             .build()
             .expect("reqwest client");
 
+        let boundaries = match cfg.batch_mode {
+            BatchMode::Fifo => &[][..],
+            BatchMode::Bucketed => &cfg.bucket_boundaries[..],
+        };
+        let wait = AdaptiveWait::from_config(cfg);
+        if wait.is_none() {
+            metrics.set_wait_time(Duration::from_millis(cfg.max_wait_time_ms));
+        }
+
         Self {
             rx,
+            rx_high: None,
+            high_turn: false,
             client,
-            tei_url: cfg.tei_url.clone(),
-            max_wait_time: Duration::from_millis(cfg.max_wait_time),
-            max_batch_size: cfg.max_batch_size,
-            inflight: Arc::new(Semaphore::new(cfg.batch_concurrency)),
+            upstreams,
+            normalize: cfg.normalize,
+            queue: BatchQueue::new(boundaries, QueueLimits::from_config(cfg)),
+            in_flight: Arc::default(),
+            cache: None,
+            breaker: None,
+            retry: None,
+            wait,
+            shutdown: None,
+            metrics,
         }
     }
 
-    /// Spawn the accumulator loop. Each flush is executed in its own task.
+    /// Drains the queue without waiting for batches to fill once `shutdown` is triggered.
+    pub fn with_shutdown(mut self, shutdown: Arc<Shutdown>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Retries failed batches according to `retry`; `None` fails them right away.
+    pub fn with_retries(mut self, retry: Option<Arc<RetryPolicy>>) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Fails batches immediately while `breaker` is open.
+    pub fn with_circuit_breaker(mut self, breaker: Option<Arc<CircuitBreaker>>) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    /// Stores the embeddings of successful flushes in `cache`.
+    pub fn with_cache(mut self, cache: Option<Arc<EmbeddingCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Shares batches between tenants according to their configured weights.
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.queue = self.queue.with_tenants(tenants);
+        self
+    }
+
+    /// Receives high-priority items on a channel of their own. Without it, high-priority
+    /// items still get precedence inside the queue, but wait behind everything in `rx` first.
+    pub fn with_priority_lane(mut self, rx_high: mpsc::Receiver<BatchItem>) -> Self {
+        self.rx_high = Some(rx_high);
+        self
+    }
+
+    /// Spawn the accumulator loop. Each flush is executed in its own task. If the loop panics,
+    /// it is started again on the same channels after a pause, so senders do not see the batcher
+    /// gone. One that keeps panicking, `MAX_RESTARTS` times within `RESTART_WINDOW`, is given up on.
     pub fn run(mut self) {
         tokio::spawn(async move {
-            while let Some(batch) = self.receive_batch().await {
-                self.send_batch(batch);
+            let mut restarts = std::collections::VecDeque::new();
+            while let Err(panic) = catch_unwind(self.accumulate()).await {
+                let now = Instant::now();
+                restarts.retain(|at| now - *at < RESTART_WINDOW);
+                restarts.push_back(now);
+
+                self.restart(panic_message(&*panic));
+                if restarts.len() > MAX_RESTARTS {
+                    self.give_up(restarts.len());
+                    return;
+                }
+                tokio::time::sleep(restart_delay(restarts.len())).await;
             }
 
             tracing::info!("batcher exiting: channel closed");
         });
     }
 
-    /// Receives and accumulates batch items until `max_batch_size` or `max_wait_time` deadline is reached.
-    async fn receive_batch(&mut self) -> Option<Vec<BatchItem>> {
-        let first = self.rx.recv().await?;
-        let mut batch = Vec::with_capacity(self.max_batch_size);
-        batch.push(first);
+    async fn accumulate(&mut self) {
+        loop {
+            if let Err(e) = self.wait_for_capacity().await {
+                self.fail_held(e);
+                return;
+            }
+            let Some(batch) = self.receive_batch().await else {
+                return;
+            };
+
+            // Some replica had room a moment ago, so this rarely waits; the replica is only
+            // chosen now, among those in rotation
+            match self.upstreams.acquire().await {
+                Ok(lease) => self.send_batch(batch, lease),
+                Err(e) => {
+                    for item in batch {
+                        let _ = item.resp.send(Err(e.clone()));
+                    }
+                    self.fail_held(e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Waits until some replica has room for the next batch. Items keep arriving into the queue
+    /// meanwhile, so under load the backlog waits there, where priorities and tenant shares decide
+    /// what the batch formed once a replica frees up is made of, rather than as batches queued for
+    /// a permit.
+    async fn wait_for_capacity(&mut self) -> Result<(), ProxyError> {
+        let upstreams = self.upstreams.clone();
+        let mut ready = std::pin::pin!(upstreams.ready());
+
+        while self.queue.len() < self.queue.capacity() {
+            tokio::select! {
+                biased;
+                ready = &mut ready => return ready,
+                item = self.recv() => match item {
+                    Some(item) => self.admit(item),
+                    None => break, // closed; whatever is held still needs a replica
+                },
+            }
+        }
+
+        ready.await
+    }
+
+    /// Answers every held item with `error`, once the upstream is gone for good.
+    fn fail_held(&mut self, error: ProxyError) {
+        for item in self.queue.drain() {
+            let _ = item.resp.send(Err(error.clone()));
+        }
+        self.report_held();
+    }
+
+    /// Recovers from a panic of the accumulator loop. The queue may be in any state and its
+    /// items may be what made the loop panic, so they are failed rather than batched again.
+    fn restart(&mut self, cause: &str) {
+        let held = self.queue.drain();
+        self.report_held();
+        self.metrics.add_batcher_restarts(1);
+        tracing::error!(cause, held = held.len(), "batcher panicked, restarting");
+
+        for item in held {
+            let _ = item.resp.send(Err(ProxyError::BatcherUnavailable));
+        }
+    }
+
+    /// Closes the channels after too many restarts, failing whatever they still hold. Senders see
+    /// the batcher gone from then on, and `/ready` fails.
+    fn give_up(&mut self, panics: usize) {
+        self.rx.close();
+        if let Some(rx_high) = &mut self.rx_high {
+            rx_high.close();
+        }
 
-        let deadline = Instant::now() + self.max_wait_time;
+        let mut failed = 0;
+        while let Ok(item) = self.try_recv() {
+            let _ = item.resp.send(Err(ProxyError::BatcherUnavailable));
+            failed += 1;
+        }
+        tracing::error!(
+            panics,
+            window_secs = RESTART_WINDOW.as_secs(),
+            failed,
+            "batcher keeps panicking, giving up"
+        );
+    }
 
+    /// Receives and accumulates batch items until some bucket of the queue reaches
+    /// `max_batch_size`, `max_batch_tokens` or its deadline: `max_wait_time_ms` after its oldest
+    /// item arrived, or earlier when an item's own deadline is about to expire.
+    async fn receive_batch(&mut self) -> Option<Vec<BatchItem>> {
         loop {
-            // Fast-drain whatever is already queued
-            while batch.len() < self.max_batch_size {
-                match self.rx.try_recv() {
-                    Ok(item) => batch.push(item),
+            // Fast-drain whatever is already queued, but never hold more than the queue's capacity
+            // so the channel keeps applying backpressure.
+            while self.queue.len() < self.queue.capacity() {
+                match self.try_recv() {
+                    Ok(item) => self.admit(item),
                     Err(TryRecvError::Empty) => break,
-                    Err(TryRecvError::Disconnected) => return Some(batch),
+                    Err(TryRecvError::Disconnected) => return self.pop_oldest(), // closed; flush what we have
                 }
             }
 
-            if batch.len() == self.max_batch_size {
-                return Some(batch);
+            self.adapt_wait();
+            let ready = match self.is_draining() {
+                true => self.queue.pop_oldest(),
+                false => self.queue.pop_ready(Instant::now()),
+            };
+            if let Some(mut batch) = ready {
+                self.report_held();
+                // Callers may have left or run out of time while their items sat in the queue
+                prune(&mut batch, &self.metrics);
+                if !batch.is_empty() {
+                    return Some(batch);
+                }
+                continue;
             }
 
-            let now = Instant::now();
-            let remaining = deadline - now;
+            // Wait for more items or the earliest bucket deadline. If multiple new items are present,
+            // they will be processed in the next iteration. This way we can avoid busy-waiting.
+            let Some(deadline) = self.queue.next_deadline() else {
+                let item = self.recv().await?;
+                self.admit(item);
+                continue;
+            };
+
+            // A shutdown cuts the wait short, the next iteration flushes everything held
+            let shutdown = self.shutdown.clone();
+            let triggered = async {
+                match &shutdown {
+                    Some(shutdown) => shutdown.triggered().await,
+                    None => std::future::pending().await,
+                }
+            };
 
-            if now >= deadline {
-                return Some(batch);
+            if self.queue.len() >= self.queue.capacity() {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(deadline) => {}
+                    _ = triggered => {}
+                }
+                continue;
             }
 
-            // Wait for more items or deadline. If multiple new items are present,
-            // they will be processed in the next iteration. This way we can avoid busy-waiting.
-            match tokio::time::timeout(remaining, self.rx.recv()).await {
-                Ok(Some(item)) => {
-                    batch.push(item);
+            tokio::select! {
+                received = tokio::time::timeout_at(deadline, self.recv()) => match received {
+                    Ok(Some(item)) => self.admit(item),
+                    Ok(None) => return self.pop_oldest(), // closed; flush what we have
+                    Err(_) => {}                                // deadline reached
+                },
+                _ = triggered => {}
+            }
+        }
+    }
 
-                    if batch.len() == self.max_batch_size {
-                        return Some(batch);
+    fn is_draining(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(|s| s.is_draining())
+    }
+
+    /// Updates the queue's wait window from the current load, with adaptive wait on.
+    fn adapt_wait(&mut self) {
+        let Some(wait) = &self.wait else {
+            return;
+        };
+
+        let max_wait = wait.wait(
+            self.queue.len(),
+            self.queue.max_batch_size(),
+            self.upstreams.service_time(),
+        );
+        self.queue.set_max_wait(max_wait);
+        self.metrics.set_wait_time(max_wait);
+    }
+
+    /// Next item already waiting in either channel. The channels take turns, so a backlog in one
+    /// never keeps the other out of the queue once it is close to capacity.
+    fn try_recv(&mut self) -> Result<BatchItem, TryRecvError> {
+        let Some(rx_high) = &mut self.rx_high else {
+            return self.rx.try_recv();
+        };
+
+        self.high_turn = !self.high_turn;
+        if self.high_turn {
+            return rx_high.try_recv().or_else(|_| self.rx.try_recv());
+        }
+
+        match self.rx.try_recv() {
+            Ok(item) => Ok(item),
+            Err(e) => rx_high.try_recv().map_err(|_| e),
+        }
+    }
+
+    /// Next item from either channel. `None` once `rx` is closed.
+    async fn recv(&mut self) -> Option<BatchItem> {
+        let Some(rx_high) = &mut self.rx_high else {
+            return self.rx.recv().await;
+        };
+
+        tokio::select! {
+            Some(item) = rx_high.recv() => Some(item),
+            item = self.rx.recv() => item,
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<Vec<BatchItem>> {
+        let batch = self.queue.pop_oldest();
+        self.report_held();
+        batch
+    }
+
+    /// Publishes how many items the queue holds, for `/ready` and `/metrics`.
+    fn report_held(&self) {
+        for priority in [Priority::High, Priority::Low] {
+            self.metrics.set_held(priority, self.queue.len_of(priority));
+        }
+    }
+
+    /// Queues a received item, unless its caller is already gone or out of time.
+    fn admit(&mut self, item: BatchItem) {
+        let now = Instant::now();
+        if let Some(wait) = &mut self.wait {
+            wait.arrived(now);
+        }
+        if let Some(item) = screen(item, now, &self.metrics) {
+            self.queue.push(item, now);
+            self.report_held();
+        }
+    }
+
+    /// Sends batch to the upstream service on `lease` with spawned task, so accumulator
+    /// can immediately continue with subsequent items. Each unique input is sent once;
+    /// items identical to one already in flight wait for that flight instead.
+    fn send_batch(&mut self, mut batch: Vec<BatchItem>, lease: Lease) {
+        let now = Instant::now();
+        let tokens: usize = batch.iter().map(|item| item.tokens).sum();
+        self.metrics.batch_size.observe(batch.len() as u64);
+        self.metrics.batch_tokens.observe(tokens as u64);
+
+        let span = tracing::info_span!("flush", batch = batch.len(), tokens);
+        for item in &mut batch {
+            self.metrics.queue_wait.observe_duration(now - item.enqueued);
+            // The queue wait is over
+            telemetry::link(&span, &std::mem::replace(&mut item.span, Span::none()));
+        }
+
+        let inputs = self.in_flight.join(batch, &self.metrics);
+        if inputs.is_empty() {
+            tracing::debug!("flush skipped, all items joined inputs already in flight");
+            return;
+        }
+
+        let call = match self.breaker.as_ref().map(|b| b.allow()).transpose() {
+            Ok(call) => call,
+            Err(e) => {
+                for input in &inputs {
+                    self.in_flight.complete(input, Err(e.clone()));
+                }
+
+                tracing::debug!(batch = inputs.len(), "flush rejected, circuit open");
+                return;
+            }
+        };
+
+        let flush = Flush {
+            client: self.client.clone(),
+            upstreams: self.upstreams.clone(),
+            in_flight: self.in_flight.clone(),
+            cache: self.cache.clone(),
+            retry: self.retry.clone(),
+            normalize: self.normalize,
+            metrics: self.metrics.clone(),
+        };
+
+        // A panicking flush would leave its waiters, and every later identical input, waiting forever
+        let owned = inputs.clone();
+        let in_flight = self.in_flight.clone();
+        let metrics = self.metrics.clone();
+        let guard = self.shutdown.as_ref().map(|s| s.track());
+        tokio::spawn(
+            async move {
+                if let Err(panic) = catch_unwind(flush.run(inputs, call, lease)).await {
+                    metrics.add_flush_panics(1);
+                    tracing::error!(cause = panic_message(&*panic), batch = owned.len(), "flush panicked");
+
+                    for input in &owned {
+                        in_flight.complete(input, Err(ProxyError::BatcherUnavailable));
                     }
                 }
-                Ok(None) => return Some(batch), // closed; flush what we have
-                Err(_) => return Some(batch),   // deadline reached
+                drop(guard);
             }
+            .instrument(span),
+        );
+    }
+}
+
+/// What a flush task needs from the [`Batcher`].
+struct Flush {
+    client: Client,
+    upstreams: Arc<Upstreams>,
+    in_flight: Arc<InFlight>,
+    cache: Option<Arc<EmbeddingCache>>,
+    retry: Option<Arc<RetryPolicy>>,
+    normalize: bool,
+    metrics: Arc<Metrics>,
+}
+
+impl Flush {
+    /// Sends the owned `inputs` upstream on `lease` and answers their waiters.
+    async fn run(self, inputs: Vec<String>, call: Option<Call>, lease: Lease) {
+        if let Some(retry) = &self.retry {
+            retry.deposit();
         }
+
+        let Some((inputs, result, lease)) = self.send(inputs, Some(lease)).await else {
+            return;
+        };
+        if let Some(call) = call {
+            call.record(&result);
+        }
+
+        self.settle(inputs, result, lease).await;
     }
 
-    /// Sends batch to the upstream service with spawned task, so accumulator
-    /// can immediately continue with subsequent items.
-    fn send_batch(&mut self, batch: Vec<BatchItem>) {
-        let client = self.client.clone();
-        let embed_url = format!("{}/embed", self.tei_url);
-        let inflight = self.inflight.clone();
+    /// Sends `inputs` upstream, retrying retryable failures, and returns the inputs that were
+    /// still wanted with the outcome. `None` if there is nobody left to answer. The first attempt
+    /// goes out on `lease` if given; retries and halves of a rejected batch acquire their own.
+    async fn send(
+        &self,
+        mut inputs: Vec<String>,
+        mut lease: Option<Lease>,
+    ) -> Option<(Vec<String>, Result<Vec<Vec<f32>>, ProxyError>, Lease)> {
+        let mut retries = 0;
 
-        tokio::spawn(async move {
-            let _permit = match inflight.acquire_owned().await {
-                Ok(p) => p,
-                Err(_) => {
-                    for item in batch {
-                        let _ = item.resp.send(Err(ProxyError::ServiceShutdown));
+        loop {
+            let acquired = match lease.take() {
+                Some(lease) => Ok(lease),
+                None => self.upstreams.acquire().await,
+            };
+            let lease = match acquired {
+                Ok(lease) => lease,
+                Err(e) => {
+                    for input in &inputs {
+                        self.in_flight.complete(input, Err(e.clone()));
                     }
 
                     tracing::warn!("service shutting down");
-                    return;
+                    return None;
                 }
             };
 
-            #[derive(serde::Serialize)]
-            struct EmbReq<'a> {
-                inputs: Vec<&'a str>,
+            // Waiting for a permit or a retry can take a while under load; skip work nobody will read
+            inputs = self.in_flight.prune(inputs, &self.metrics);
+            if inputs.is_empty() {
+                tracing::debug!("flush skipped, all items cancelled or expired");
+                return None;
+            }
+
+            let started = Instant::now();
+            let attempt = tracing::info_span!(
+                "upstream",
+                otel.kind = "client",
+                upstream = lease.url(),
+                retry = retries
+            );
+            let result = self
+                .embed(lease.url(), &inputs)
+                .instrument(attempt)
+                .await
+                .and_then(|embs| {
+                    if embs.len() != inputs.len() {
+                        return Err(ProxyError::CountMismatch {
+                            expected: inputs.len(),
+                            got: embs.len(),
+                        });
+                    }
+                    Ok(embs)
+                });
+            self.metrics.upstream_latency.observe_duration(started.elapsed());
+            match &result {
+                Ok(_) => lease.observe(started.elapsed()),
+                Err(e) if e.is_upstream_failure() => lease.fail(),
+                Err(ProxyError::Upstream { code: 429, .. }) => lease.throttled(),
+                Err(_) => {}
             }
 
-            let req = EmbReq {
-                inputs: batch.iter().map(|b| b.input.as_str()).collect(),
+            let Err(e) = &result else {
+                return Some((inputs, result, lease));
             };
-            let resp = client.post(embed_url).json(&req).send().await;
-            let result: Result<Vec<Vec<f32>>, ProxyError> = match resp {
-                Ok(r) if r.status().is_success() => r.json().await.map_err(ProxyError::from),
-                Ok(r) => {
-                    let code = r.status().as_u16();
-                    let body = r.text().await.unwrap_or_default();
-
-                    Err(ProxyError::Upstream { code, body })
-                }
-                Err(e) => Err(ProxyError::from(e)),
+            let Some(delay) = self.backoff(e, retries, &inputs) else {
+                return Some((inputs, result, lease));
             };
 
-            match result {
-                Ok(embs) if embs.len() == batch.len() => {
-                    let item_count = batch.len();
+            retries += 1;
+            self.metrics.add_retries(1);
+            tracing::warn!(error = %e, upstream = lease.url(), retry = retries, delay_ms = delay.as_millis() as u64, "flush failed, retrying");
+            // Another replica may pick up the retry; this one's permit is not held while waiting
+            drop(lease);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Answers the waiters of `inputs`. A batch TEI rejected as a whole is split in halves that
+    /// are sent again, recursively, so that only the offending inputs fail.
+    async fn settle(&self, inputs: Vec<String>, result: Result<Vec<Vec<f32>>, ProxyError>, lease: Lease) {
+        match result {
+            Ok(embs) => {
+                let input_count = inputs.len();
 
-                    for (item, emb) in batch.into_iter().zip(embs) {
-                        let _ = item.resp.send(Ok(emb));
+                for (input, emb) in inputs.iter().zip(embs) {
+                    if let Some(cache) = &self.cache {
+                        cache.insert(input, emb.clone());
                     }
-
-                    tracing::info!(batch = %item_count, "flush_ok");
+                    self.in_flight.complete(input, Ok(emb));
                 }
-                Ok(embs) => {
-                    let got = embs.len();
-                    let exp = batch.len();
 
-                    for item in batch {
-                        let _ = item.resp.send(Err(ProxyError::CountMismatch { expected: exp, got }));
-                    }
+                tracing::info!(batch = %input_count, upstream = lease.url(), limit = lease.limit(), "flush_ok");
+            }
+            Err(e) if e.is_batch_rejection() && inputs.len() > 1 => {
+                tracing::warn!(error = %e, batch = inputs.len(), upstream = lease.url(), "batch rejected, bisecting");
+                drop(lease);
+                self.metrics.add_bisections(1);
 
-                    tracing::error!("embedding count mismatch: got {got}, expected {exp}");
+                let mut left = inputs;
+                let right = left.split_off(left.len() / 2);
+                tokio::join!(self.bisect(left), self.bisect(right));
+            }
+            Err(e) => {
+                for input in &inputs {
+                    self.in_flight.complete(input, Err(e.clone()));
                 }
-                Err(e) => {
-                    for item in batch {
-                        let _ = item.resp.send(Err(e.clone()));
-                    }
 
-                    tracing::error!(error = %e, "flush_err");
-                }
+                tracing::error!(error = %e, batch = inputs.len(), upstream = lease.url(), "flush_err");
             }
-        });
+        }
+    }
+
+    /// Sends one half of a rejected batch, see [`Flush::settle`].
+    async fn bisect(&self, inputs: Vec<String>) {
+        if let Some((inputs, result, lease)) = self.send(inputs, None).await {
+            Box::pin(self.settle(inputs, result, lease)).await;
+        }
+    }
+
+    /// One upstream call for `inputs`.
+    async fn embed(&self, url: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, ProxyError> {
+        #[derive(serde::Serialize)]
+        struct EmbReq<'a> {
+            inputs: &'a [String],
+            normalize: bool,
+        }
+
+        let req = EmbReq {
+            inputs,
+            normalize: self.normalize,
+        };
+        // Lets TEI's own spans, if it exports any, join the trace of this call
+        let mut headers = HeaderMap::new();
+        telemetry::inject(&Span::current(), &mut headers);
+
+        let resp = self
+            .client
+            .post(format!("{url}/embed"))
+            .headers(headers)
+            .json(&req)
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            let code = resp.status().as_u16();
+            let body = resp.text().await.unwrap_or_default();
+
+            return Err(ProxyError::Upstream { code, body });
+        }
+
+        Ok(resp.json().await?)
+    }
+
+    /// How long to wait before retrying after `error`, or `None` to give up on it.
+    fn backoff(&self, error: &ProxyError, retries: u32, inputs: &[String]) -> Option<Duration> {
+        let retry = self.retry.as_ref()?;
+        if !error.is_retryable() || retries >= retry.max_retries {
+            return None;
+        }
+
+        // Pointless if every caller will have given up by then
+        let delay = retry.backoff(retries);
+        if self
+            .in_flight
+            .latest_deadline(inputs)
+            .is_some_and(|deadline| Instant::now() + delay >= deadline)
+        {
+            return None;
+        }
+
+        if !retry.withdraw() {
+            tracing::warn!(error = %error, "retry budget exhausted");
+            return None;
+        }
+
+        Some(delay)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env;
+    use crate::limiter::ConcurrencyLimits;
+    use crate::upstream::Balance;
     use tokio::sync::mpsc;
     use tokio::time::Duration;
 
+    /// Serves `/embed` on a local port, answering every call with `reply(inputs)`.
+    async fn fake_tei(reply: impl Fn(&[String]) -> (u16, String) + Send + Sync + 'static) -> String {
+        fake_tei_with_latency(Duration::ZERO, reply).await
+    }
+
+    /// Like [`fake_tei`], but every call takes `latency` to answer.
+    async fn fake_tei_with_latency(
+        latency: Duration,
+        reply: impl Fn(&[String]) -> (u16, String) + Send + Sync + 'static,
+    ) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        #[derive(serde::Deserialize)]
+        struct EmbReq {
+            inputs: Vec<String>,
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let reply = Arc::new(reply);
+
+        tokio::spawn(async move {
+            while let Ok((mut sock, _)) = listener.accept().await {
+                let reply = reply.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let body = loop {
+                        let mut chunk = [0; 4096];
+                        let n = sock.read(&mut chunk).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        buf.extend_from_slice(&chunk[..n]);
+
+                        let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                            continue;
+                        };
+                        let head = String::from_utf8_lossy(&buf[..end]).to_ascii_lowercase();
+                        let len: usize = head
+                            .lines()
+                            .find_map(|l| l.strip_prefix("content-length:"))
+                            .map_or(0, |v| v.trim().parse().unwrap());
+                        if buf.len() >= end + 4 + len {
+                            break &buf[end + 4..end + 4 + len];
+                        }
+                    };
+
+                    let req: EmbReq = serde_json::from_slice(body).unwrap();
+                    let (code, body) = reply(&req.inputs);
+                    tokio::time::sleep(latency).await;
+                    let resp = format!(
+                        "HTTP/1.1 {code} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    sock.write_all(resp.as_bytes()).await.unwrap();
+                });
+            }
+        });
+
+        url
+    }
+
+    /// Nothing listens there, so every call fails with `ProxyError::Request`.
+    const UNROUTABLE: &str = "http://127.0.0.1:12345";
+
+    type Waiter = oneshot::Receiver<Result<Vec<f32>, ProxyError>>;
+
+    fn items(inputs: &[&str]) -> (Vec<BatchItem>, Vec<Waiter>) {
+        inputs.iter().map(|input| BatchItem::test(input, 1)).unzip()
+    }
+
+    fn upstreams(url: &str, permits: usize) -> Arc<Upstreams> {
+        Arc::new(Upstreams::new(
+            &[url.to_string()],
+            ConcurrencyLimits::fixed(permits),
+            Balance::RoundRobin,
+        ))
+    }
+
     // Small helper to build a Batcher with hand-picked params.
     fn mk_batcher(rx: mpsc::Receiver<BatchItem>, max_batch: usize, max_wait_ms: u64) -> Batcher {
+        mk_batcher_with_tokens(rx, max_batch, max_wait_ms, usize::MAX)
+    }
+
+    fn mk_batcher_with_tokens(
+        rx: mpsc::Receiver<BatchItem>,
+        max_batch: usize,
+        max_wait_ms: u64,
+        max_tokens: usize,
+    ) -> Batcher {
         Batcher {
             rx,
+            rx_high: None,
+            high_turn: false,
             client: Client::builder().build().unwrap(),
-            tei_url: env::var("TEI_URL").expect("TEI_URL must be set"),
-            max_wait_time: Duration::from_millis(max_wait_ms),
-            max_batch_size: max_batch,
-            inflight: Arc::new(Semaphore::new(8)),
+            upstreams: upstreams(UNROUTABLE, 8),
+            normalize: true,
+            queue: BatchQueue::new(
+                &[],
+                QueueLimits {
+                    max_batch_size: max_batch,
+                    max_batch_tokens: max_tokens,
+                    max_wait: Duration::from_millis(max_wait_ms),
+                    high_priority_max_wait: Duration::from_millis(max_wait_ms),
+                    low_priority_min_slots: 0,
+                    deadline_margin: Duration::from_millis(10),
+                    max_held: 0,
+                },
+            ),
+            in_flight: Arc::default(),
+            cache: None,
+            breaker: None,
+            retry: None,
+            wait: None,
+            shutdown: None,
+            metrics: Arc::new(Metrics::default()),
         }
     }
 
     #[tokio::test]
     async fn receive_batch_respects_size_cap() {
         let (tx, rx) = mpsc::channel::<BatchItem>(64);
+        // Keep the callers waiting, dropped receivers count as cancelled
+        let mut rxs = Vec::new();
         // Pre-fill > max_batch items quickly
         for i in 0..10 {
-            let (txr, _rxr) = oneshot::channel();
-            tx.send(BatchItem {
-                input: format!("i-{i}"),
-                resp: txr,
-            })
-            .await
-            .unwrap();
+            let (item, rxr) = BatchItem::test(&format!("i-{i}"), 1);
+            rxs.push(rxr);
+            tx.send(item).await.unwrap();
         }
 
         let mut b = mk_batcher(rx, 4, 500);
@@ -228,18 +1116,34 @@ mod tests {
         assert_eq!(batch.len(), 4, "should flush exactly at max_batch_size");
     }
 
+    #[tokio::test]
+    async fn receive_batch_respects_token_budget() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(64);
+        let mut rxs = Vec::new();
+        for (i, tokens) in [10, 10, 10, 40, 5].into_iter().enumerate() {
+            let (item, rxr) = BatchItem::test(&format!("i-{i}"), tokens);
+            rxs.push(rxr);
+            tx.send(item).await.unwrap();
+        }
+
+        let mut b = mk_batcher_with_tokens(rx, 32, 20, 25);
+        let sizes = |batch: Vec<BatchItem>| batch.iter().map(|i| i.tokens).collect::<Vec<_>>();
+
+        // 10 + 10 fits, the third 10 would exceed 25 and opens the next batch
+        assert_eq!(sizes(b.receive_batch().await.unwrap()), vec![10, 10]);
+        assert_eq!(sizes(b.receive_batch().await.unwrap()), vec![10]);
+        // An item above the budget on its own is still sent, alone
+        assert_eq!(sizes(b.receive_batch().await.unwrap()), vec![40]);
+        assert_eq!(sizes(b.receive_batch().await.unwrap()), vec![5]);
+    }
+
     #[tokio::test]
     async fn receive_batch_respects_timeout() {
         let (tx, rx) = mpsc::channel::<BatchItem>(64);
 
         // Send exactly one, then wait longer than max_wait
-        let (txr, _rxr) = oneshot::channel();
-        tx.send(BatchItem {
-            input: "first".into(),
-            resp: txr,
-        })
-        .await
-        .unwrap();
+        let (item, _rxr) = BatchItem::test("first", 1);
+        tx.send(item).await.unwrap();
 
         let mut b = mk_batcher(rx, 8, 30);
         let t0 = Instant::now();
@@ -253,27 +1157,38 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn a_fixed_wait_is_reported_as_the_wait_window() {
+        let cfg = AppConfig {
+            max_wait_time_ms: 8,
+            adaptive_wait: false,
+            ..Default::default()
+        };
+        let (_tx, rx) = mpsc::channel::<BatchItem>(1);
+        let metrics = Arc::new(Metrics::default());
+        let _b = Batcher::new(&cfg, rx, upstreams(UNROUTABLE, 1), metrics.clone());
+
+        assert_eq!(metrics.wait_time_us.load(std::sync::atomic::Ordering::Relaxed), 8_000);
+    }
+
     #[tokio::test]
     async fn send_batch_fans_out_error_to_all_waiters() {
-        // Point upstream to an unroutable endpoint to force a Request error
+        // The upstream is unroutable, which forces a Request error
         let (_tx, rx) = mpsc::channel::<BatchItem>(1);
         let mut b = mk_batcher(rx, 4, 10);
-        b.tei_url = "http://127.0.0.1:12345".to_string();
 
         // Build a manual batch of 3 items with receivers we can await
         let mut rxs = Vec::new();
         let mut batch = Vec::new();
         for i in 0..3 {
-            let (txr, rxr) = oneshot::channel();
-            batch.push(BatchItem {
-                input: format!("x-{i}"),
-                resp: txr,
-            });
+            let (item, rxr) = BatchItem::test(&format!("x-{i}"), 1);
+            batch.push(item);
             rxs.push(rxr);
         }
 
         // Call send_batch (spawns a task)
-        b.send_batch(batch);
+        let lease = b.upstreams.acquire().await.unwrap();
+        b.send_batch(batch, lease);
 
         // All receivers should error
         for rx in rxs {
@@ -283,17 +1198,233 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn cancelled_items_never_reach_a_batch() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(8);
+        let mut kept = Vec::new();
+        for i in 0..4 {
+            let (item, rxr) = BatchItem::test(&format!("c-{i}"), 1);
+            tx.send(item).await.unwrap();
+            // Callers of odd items hang up while queued
+            if i % 2 == 0 {
+                kept.push(rxr);
+            }
+        }
+
+        let mut b = mk_batcher(rx, 4, 10);
+        let batch = b.receive_batch().await.expect("some batch");
+        let inputs: Vec<_> = batch.iter().map(|i| i.input.as_str()).collect();
+
+        assert_eq!(inputs, vec!["c-0", "c-2"]);
+        assert_eq!(b.metrics.cancelled.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn items_cancelled_while_waiting_for_a_permit_never_reach_a_batch() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(4);
+        let mut b = mk_batcher(rx, 4, 10);
+        b.upstreams = upstreams(UNROUTABLE, 1);
+        let busy = b.upstreams.acquire().await.unwrap();
+
+        let (batch, mut waiters) = items(&["kept", "gone"]);
+        for item in batch {
+            tx.send(item).await.unwrap();
+        }
+        // The batcher takes both items in while the permit is busy
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), b.wait_for_capacity())
+                .await
+                .is_err()
+        );
+        assert_eq!(b.queue.len(), 2);
+
+        drop(waiters.pop());
+        drop(busy);
+        b.wait_for_capacity().await.unwrap();
+        let batch = b.receive_batch().await.expect("some batch");
+
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].input, "kept");
+        assert_eq!(b.metrics.cancelled.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+
+    type Calls = Arc<std::sync::Mutex<Vec<Vec<String>>>>;
+
+    /// A batcher for 4 items of 1 token (4 characters) per batch, in front of a TEI that takes
+    /// 30ms per call and one call at a time, so a backlog builds up; and the inputs of every call.
+    async fn saturated_batcher(rx: mpsc::Receiver<BatchItem>) -> (Batcher, Calls) {
+        let calls = Calls::default();
+        let seen = calls.clone();
+        let url = fake_tei_with_latency(Duration::from_millis(30), move |inputs| {
+            seen.lock().unwrap().push(inputs.to_vec());
+            (200, serde_json::to_string(&vec![vec![1.0]; inputs.len()]).unwrap())
+        })
+        .await;
+
+        let mut b = mk_batcher_with_tokens(rx, 4, 1, 4);
+        b.upstreams = upstreams(&url, 1);
+        b.queue = BatchQueue::new(
+            &[],
+            QueueLimits {
+                max_batch_size: 4,
+                max_batch_tokens: 4,
+                max_wait: Duration::from_millis(1),
+                high_priority_max_wait: Duration::from_millis(1),
+                low_priority_min_slots: 0,
+                deadline_margin: Duration::from_millis(10),
+                max_held: 64,
+            },
+        );
+        (b, calls)
+    }
+
+    /// Sends 20 items of `opts` in the background, then gives the first batch time to go upstream.
+    async fn backlog(sender: &Arc<BatchSender>, opts: RequestOptions) -> Vec<tokio::task::JoinHandle<()>> {
+        let tasks = (0..20)
+            .map(|i| {
+                let (sender, opts) = (sender.clone(), opts.clone());
+                tokio::spawn(async move { sender.request(format!("b-{i:02}"), &opts).await.map(drop).unwrap() })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        tasks
+    }
+
+    /// Index of the upstream call that carried `input`.
+    fn call_of(calls: &Calls, input: &str) -> Option<usize> {
+        calls.lock().unwrap().iter().position(|c| c.iter().any(|i| i == input))
+    }
+
+    #[tokio::test]
+    async fn high_priority_overtakes_a_backlog_waiting_for_a_saturated_upstream() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(64);
+        let (tx_high, rx_high) = mpsc::channel::<BatchItem>(64);
+        let sender = Arc::new(BatchSender::new(tx).with_priority_lane(tx_high));
+        let (b, calls) = saturated_batcher(rx).await;
+        b.with_priority_lane(rx_high).run();
+
+        let bulk = backlog(&sender, RequestOptions::default()).await;
+        let interactive = RequestOptions {
+            priority: Priority::High,
+            ..Default::default()
+        };
+        assert_eq!(sender.request("q-hi".into(), &interactive).await.unwrap(), vec![1.0]);
+
+        // The first batch was already upstream when the query arrived; it goes out next, not after the backlog
+        assert_eq!(call_of(&calls, "q-hi"), Some(1), "{:?}", calls.lock().unwrap());
+        for task in bulk {
+            task.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn a_quiet_tenant_overtakes_a_noisy_tenants_backlog() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(64);
+        let sender = Arc::new(BatchSender::new(tx));
+        let (b, calls) = saturated_batcher(rx).await;
+        b.run();
+
+        let tenant = |name: &str| RequestOptions {
+            tenant: Some(name.into()),
+            ..Default::default()
+        };
+        let noisy = backlog(&sender, tenant("noisy")).await;
+        assert_eq!(
+            sender.request("q-00".into(), &tenant("quiet")).await.unwrap(),
+            vec![1.0]
+        );
+
+        // Shares of the next batch are split between both tenants, whatever the noisy one has queued
+        assert_eq!(call_of(&calls, "q-00"), Some(1), "{:?}", calls.lock().unwrap());
+        for task in noisy {
+            task.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn a_slow_replica_holds_up_only_its_own_batch() {
+        let ok = |inputs: &[String]| (200, serde_json::to_string(&vec![vec![1.0]; inputs.len()]).unwrap());
+        let slow = fake_tei_with_latency(Duration::from_secs(3), ok).await;
+        let fast = fake_tei(ok).await;
+
+        let (tx, rx) = mpsc::channel::<BatchItem>(8);
+        let sender = Arc::new(BatchSender::new(tx));
+        let mut b = mk_batcher(rx, 1, 1);
+        b.upstreams = Arc::new(Upstreams::new(
+            &[slow, fast],
+            ConcurrencyLimits::fixed(1),
+            Balance::RoundRobin,
+        ));
+        b.run();
+
+        // Two batches in the background, so one of them occupies the slow replica
+        let opts = RequestOptions::default();
+        for input in ["a", "b"] {
+            let (sender, opts) = (sender.clone(), opts.clone());
+            tokio::spawn(async move { sender.request(input.into(), &opts).await });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let t0 = Instant::now();
+        for input in ["c", "d", "e"] {
+            sender.request(input.into(), &opts).await.unwrap();
+        }
+        assert!(t0.elapsed() < Duration::from_secs(1), "waited {:?}", t0.elapsed());
+    }
+
+    #[tokio::test]
+    async fn receive_batch_flushes_early_for_tight_deadline_and_rejects_expired() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(8);
+        let mut b = mk_batcher(rx, 8, 500);
+        let now = Instant::now();
+
+        let (mut expired, expired_rx) = BatchItem::test("too-late", 1);
+        expired.deadline = Some(now);
+        tx.send(expired).await.unwrap();
+        let (mut tight, _tight_rx) = BatchItem::test("tight", 1);
+        tight.deadline = Some(now + Duration::from_millis(40));
+        tx.send(tight).await.unwrap();
+
+        let batch = b.receive_batch().await.expect("some batch");
+
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].input, "tight");
+        assert!(
+            now.elapsed() < Duration::from_millis(500),
+            "should not wait for max_wait"
+        );
+        assert!(matches!(expired_rx.await.unwrap(), Err(ProxyError::DeadlineExceeded)));
+    }
+
+    #[tokio::test]
+    async fn high_priority_lane_overtakes_a_backlog() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(16);
+        let (tx_high, rx_high) = mpsc::channel::<BatchItem>(16);
+        let sender = BatchSender::new(tx).with_priority_lane(tx_high);
+        let mut b = mk_batcher(rx, 2, 10).with_priority_lane(rx_high);
+
+        let bulk = RequestOptions::default();
+        let interactive = RequestOptions {
+            priority: Priority::High,
+            ..Default::default()
+        };
+        let mut rxs = Vec::new();
+        for i in 0..4 {
+            rxs.push(sender.enqueue(format!("bulk-{i}"), &bulk).await.unwrap());
+        }
+        rxs.push(sender.enqueue("query".into(), &interactive).await.unwrap());
+
+        let batch = b.receive_batch().await.expect("some batch");
+        let inputs: Vec<_> = batch.iter().map(|i| i.input.as_str()).collect();
+        assert_eq!(inputs, vec!["query", "bulk-0"]);
+    }
+
     #[tokio::test]
     async fn receive_batch_then_channel_close_returns_none_next_time() {
         let (tx, rx) = mpsc::channel::<BatchItem>(4);
         // Send one item
-        let (txr, _rxr) = oneshot::channel();
-        tx.send(BatchItem {
-            input: "one".into(),
-            resp: txr,
-        })
-        .await
-        .unwrap();
+        let (item, _rxr) = BatchItem::test("one", 1);
+        tx.send(item).await.unwrap();
         drop(tx); // close channel
 
         let mut b = mk_batcher(rx, 4, 50);
@@ -302,4 +1433,177 @@ mod tests {
         // Second call: should see channel closed before first receive → None
         assert!(b.receive_batch().await.is_none());
     }
+
+    #[tokio::test]
+    async fn shutdown_flushes_held_items_and_turns_new_requests_away() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(4);
+        let shutdown = Arc::new(Shutdown::default());
+        let sender = BatchSender::new(tx).with_shutdown(shutdown.clone());
+        let _held = sender.enqueue("held".into(), &RequestOptions::default()).await.unwrap();
+
+        let mut b = mk_batcher(rx, 8, 10_000).with_shutdown(shutdown.clone());
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                shutdown.trigger();
+            }
+        });
+        let t0 = Instant::now();
+        let batch = b.receive_batch().await.expect("held batch");
+        assert_eq!(batch.len(), 1);
+        assert!(t0.elapsed() < Duration::from_secs(1), "waited {:?}", t0.elapsed());
+
+        assert!(matches!(
+            sender.request("late".into(), &RequestOptions::default()).await,
+            Err(ProxyError::ServiceShutdown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn restart_after_a_panic_fails_held_items_and_keeps_receiving() {
+        let panic = catch_unwind(async { panic!("boom") }).await.unwrap_err();
+        assert_eq!(panic_message(&*panic), "boom");
+
+        let (tx, rx) = mpsc::channel::<BatchItem>(4);
+        let sender = BatchSender::new(tx);
+        let mut b = mk_batcher(rx, 8, 10_000);
+        let held = sender.enqueue("held".into(), &RequestOptions::default()).await.unwrap();
+        let item = b.recv().await.unwrap();
+        b.admit(item);
+
+        b.restart("boom");
+        assert!(matches!(held.await.unwrap(), Err(ProxyError::BatcherUnavailable)));
+        assert_eq!(b.queue.len(), 0);
+        assert_eq!(b.metrics.batcher_restarts.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        // The channels survive the restart
+        let _next = sender.enqueue("next".into(), &RequestOptions::default()).await.unwrap();
+        drop(sender);
+        let batch = b.receive_batch().await.unwrap();
+        assert_eq!(batch[0].input, "next");
+    }
+
+    #[tokio::test]
+    async fn giving_up_closes_the_channels_and_fails_what_they_hold() {
+        assert_eq!(restart_delay(1), RESTART_DELAY);
+        assert_eq!(restart_delay(MAX_RESTARTS), RESTART_DELAY * 16);
+
+        let (tx, rx) = mpsc::channel::<BatchItem>(4);
+        let sender = BatchSender::new(tx);
+        let mut b = mk_batcher(rx, 8, 10_000);
+        let queued = sender
+            .enqueue("queued".into(), &RequestOptions::default())
+            .await
+            .unwrap();
+
+        b.give_up(MAX_RESTARTS + 1);
+        assert!(matches!(queued.await.unwrap(), Err(ProxyError::BatcherUnavailable)));
+        assert!(sender.is_closed());
+        assert!(matches!(
+            sender.request("late".into(), &RequestOptions::default()).await,
+            Err(ProxyError::BatcherUnavailable)
+        ));
+    }
+
+    #[tokio::test]
+    async fn cached_inputs_skip_the_queue() {
+        let (tx, rx) = mpsc::channel::<BatchItem>(4);
+        drop(rx); // anything that reaches the channel fails with BatcherUnavailable
+        let cache = Arc::new(EmbeddingCache::new(8, 1024, Arc::default()));
+        cache.insert("hello", vec![1.0, 2.0]);
+        let sender = BatchSender::new(tx).with_cache(Some(cache));
+
+        let opts = RequestOptions::default();
+        assert_eq!(sender.request("hello".into(), &opts).await.unwrap(), vec![1.0, 2.0]);
+        assert!(matches!(
+            sender.request_many(vec!["hello".into(), "other".into()], &opts).await,
+            Err(ProxyError::BatcherUnavailable)
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_batch_retries_retryable_failures() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let seen = calls.clone();
+        let url = fake_tei(
+            move |inputs| match seen.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => (503, "overloaded".into()),
+                1 => (429, "Model is overloaded".into()),
+                _ => (200, serde_json::to_string(&vec![vec![1.0]; inputs.len()]).unwrap()),
+            },
+        )
+        .await;
+
+        let (_tx, rx) = mpsc::channel::<BatchItem>(1);
+        let retry = RetryPolicy::new(2, Duration::from_millis(1), Duration::from_millis(5), 0.0, 10.0);
+        let mut b = mk_batcher(rx, 4, 10).with_retries(Some(Arc::new(retry)));
+        b.upstreams = upstreams(&url, 8);
+
+        let (batch, rxs) = items(&["a", "b"]);
+        let lease = b.upstreams.acquire().await.unwrap();
+        b.send_batch(batch, lease);
+        for rx in rxs {
+            assert_eq!(rx.await.unwrap().unwrap(), vec![1.0]);
+        }
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+        assert_eq!(b.metrics.retries.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn send_batch_does_not_retry_client_errors() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let seen = calls.clone();
+        let url = fake_tei(move |_| {
+            seen.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            (400, "bad request".into())
+        })
+        .await;
+
+        let (_tx, rx) = mpsc::channel::<BatchItem>(1);
+        let retry = RetryPolicy::new(2, Duration::from_millis(1), Duration::from_millis(5), 0.0, 10.0);
+        let mut b = mk_batcher(rx, 4, 10).with_retries(Some(Arc::new(retry)));
+        b.upstreams = upstreams(&url, 8);
+
+        let (batch, rxs) = items(&["a"]);
+        let lease = b.upstreams.acquire().await.unwrap();
+        b.send_batch(batch, lease);
+        for rx in rxs {
+            assert!(matches!(rx.await.unwrap(), Err(ProxyError::Upstream { code: 400, .. })));
+        }
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn send_batch_bisects_rejected_batches_down_to_the_poison_input() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let seen = calls.clone();
+        let url = fake_tei(move |inputs| {
+            seen.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if inputs.iter().any(|i| i == "poison") {
+                return (422, "input is too long".into());
+            }
+            (200, serde_json::to_string(&vec![vec![1.0]; inputs.len()]).unwrap())
+        })
+        .await;
+
+        let (_tx, rx) = mpsc::channel::<BatchItem>(1);
+        let mut b = mk_batcher(rx, 4, 10);
+        b.upstreams = upstreams(&url, 8);
+
+        let (batch, rxs) = items(&["a", "poison", "b", "c"]);
+        let lease = b.upstreams.acquire().await.unwrap();
+        b.send_batch(batch, lease);
+
+        let mut results = Vec::new();
+        for rx in rxs {
+            results.push(rx.await.unwrap());
+        }
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(ProxyError::Upstream { code: 422, .. })));
+        assert!(results[2].is_ok() && results[3].is_ok());
+        assert_eq!(b.metrics.bisections.load(std::sync::atomic::Ordering::Relaxed), 2);
+        // The whole batch, both halves, then both quarters of the poisoned half
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 5);
+    }
 }
@@ -0,0 +1,317 @@
+use crate::config::AppConfig;
+use crate::disk_cache::DiskCache;
+use crate::metrics::Metrics;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::time::Instant;
+use xxhash_rust::xxh3::Xxh3;
+
+/// Embeddings waiting for the disk writer; past that, new ones are only cached in memory.
+const DISK_WRITE_QUEUE: usize = 1024;
+
+/// Identifies a cached embedding: a 128-bit hash of the model, the normalization setting and the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey(u128);
+
+impl CacheKey {
+    pub fn to_bytes(self) -> [u8; 16] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(u128::from_le_bytes(bytes))
+    }
+}
+
+struct Entry {
+    embedding: Vec<f32>,
+    inserted: Instant,
+}
+
+impl Entry {
+    fn bytes(&self) -> usize {
+        size_of_val(self.embedding.as_slice())
+    }
+}
+
+struct Inner {
+    entries: LruCache<CacheKey, Entry>,
+    /// Total size of the cached embeddings.
+    bytes: usize,
+}
+
+/// Bounded LRU cache of upstream embeddings, consulted before a request is queued.
+///
+/// Entries are evicted least recently used first once either the entry or the byte limit is
+/// exceeded; with a TTL, older entries are treated as misses and dropped on lookup. With a
+/// [`DiskCache`] behind it, memory misses fall through to disk, and the memory cache is warmed
+/// with the most recent embeddings on disk when it is created. Disk reads run on the blocking
+/// pool and writes on a thread of their own, so neither stalls the async runtime.
+pub struct EmbeddingCache {
+    model: String,
+    normalize: bool,
+    max_entries: usize,
+    max_bytes: usize,
+    ttl: Option<Duration>,
+    inner: Mutex<Inner>,
+    disk: Option<Arc<DiskCache>>,
+    /// Hands embeddings to the disk writer thread.
+    disk_writes: Option<SyncSender<(CacheKey, Vec<f32>)>>,
+    disk_writer: Option<JoinHandle<()>>,
+    metrics: Arc<Metrics>,
+}
+
+impl EmbeddingCache {
+    pub fn new(max_entries: usize, max_bytes: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            model: String::new(),
+            normalize: true,
+            max_entries,
+            max_bytes,
+            ttl: None,
+            inner: Mutex::new(Inner {
+                entries: LruCache::new(NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::MIN)),
+                bytes: 0,
+            }),
+            disk: None,
+            disk_writes: None,
+            disk_writer: None,
+            metrics,
+        }
+    }
+
+    /// Builds the cache described by `cfg`, or `None` when caching is disabled.
+    pub fn from_config(cfg: &AppConfig, metrics: Arc<Metrics>) -> Option<Arc<Self>> {
+        if cfg.cache_max_entries == 0 || cfg.cache_max_bytes == 0 {
+            return None;
+        }
+
+        let mut cache = Self::new(cfg.cache_max_entries, cfg.cache_max_bytes, metrics);
+        cache.model = cfg.model_id.clone();
+        cache.normalize = cfg.normalize;
+        cache.ttl = (cfg.cache_ttl_secs > 0).then(|| Duration::from_secs(cfg.cache_ttl_secs));
+
+        if let Some(dir) = &cfg.cache_dir {
+            match DiskCache::open(dir, cfg.cache_disk_max_bytes, cache.ttl) {
+                Ok(disk) => cache = cache.with_disk(disk),
+                Err(e) => tracing::warn!(error = %e, dir, "disk cache unavailable, caching in memory only"),
+            }
+        }
+
+        Some(Arc::new(cache))
+    }
+
+    /// Persists embeddings to `disk` and warms the memory cache from it.
+    pub fn with_disk(mut self, disk: DiskCache) -> Self {
+        let warm = disk.recent(self.max_entries, self.max_bytes);
+        let now = Instant::now();
+        for (key, embedding, age) in &warm {
+            self.remember(*key, embedding.clone(), now.checked_sub(*age).unwrap_or(now));
+        }
+
+        tracing::info!(on_disk = disk.len(), warmed = warm.len(), "disk cache opened");
+        let disk = Arc::new(disk);
+        let (tx, rx) = mpsc::sync_channel::<(CacheKey, Vec<f32>)>(DISK_WRITE_QUEUE);
+        let writer = {
+            let disk = disk.clone();
+            std::thread::Builder::new()
+                .name("disk-cache-writer".into())
+                .spawn(move || {
+                    for (key, embedding) in rx {
+                        if let Err(e) = disk.put(key, &embedding) {
+                            tracing::warn!(error = %e, "disk cache write failed");
+                        }
+                    }
+                })
+                .expect("spawn disk cache writer")
+        };
+
+        self.disk = Some(disk);
+        self.disk_writes = Some(tx);
+        self.disk_writer = Some(writer);
+        self
+    }
+
+    pub fn key(&self, input: &str) -> CacheKey {
+        let mut hasher = Xxh3::new();
+        // Length-prefixed, so no model/input split can collide with another
+        hasher.update(&(self.model.len() as u64).to_le_bytes());
+        hasher.update(self.model.as_bytes());
+        hasher.update(&[self.normalize as u8]);
+        hasher.update(input.as_bytes());
+
+        CacheKey(hasher.digest128())
+    }
+
+    pub async fn get(&self, input: &str) -> Option<Vec<f32>> {
+        let key = self.key(input);
+        let mut embedding = self.recall(key);
+        if embedding.is_none() {
+            embedding = self.load(key).await;
+        }
+
+        match embedding {
+            Some(_) => self.metrics.add_cache_hits(1),
+            None => self.metrics.add_cache_misses(1),
+        }
+        embedding
+    }
+
+    /// Stores `embedding` in memory right away, and on disk once the writer gets to it.
+    pub fn insert(&self, input: &str, embedding: Vec<f32>) {
+        let key = self.key(input);
+        if let Some(tx) = &self.disk_writes
+            && let Err(TrySendError::Full(_)) = tx.try_send((key, embedding.clone()))
+        {
+            tracing::debug!("disk cache writer behind, embedding kept in memory only");
+        }
+
+        self.remember(key, embedding, Instant::now());
+    }
+
+    /// Looks `key` up on disk, off the async runtime, and keeps a hit in memory.
+    async fn load(&self, key: CacheKey) -> Option<Vec<f32>> {
+        let disk = self.disk.clone()?;
+        let embedding = match tokio::task::spawn_blocking(move || disk.get(key)).await {
+            Ok(Ok(embedding)) => embedding?,
+            Ok(Err(e)) => {
+                tracing::warn!(error = %e, "disk cache read failed");
+                return None;
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "disk cache read panicked");
+                return None;
+            }
+        };
+
+        self.metrics.add_cache_disk_hits(1);
+        self.remember(key, embedding.clone(), Instant::now());
+        Some(embedding)
+    }
+
+    /// Looks `key` up in memory, dropping the entry if it has expired.
+    fn recall(&self, key: CacheKey) -> Option<Vec<f32>> {
+        let mut inner = self.inner.lock().expect("cache lock");
+        let now = Instant::now();
+
+        let expired = inner
+            .entries
+            .peek(&key)
+            .is_some_and(|entry| self.ttl.is_some_and(|ttl| now >= entry.inserted + ttl));
+        if expired && let Some(entry) = inner.entries.pop(&key) {
+            inner.bytes -= entry.bytes();
+        }
+
+        inner.entries.get(&key).map(|entry| entry.embedding.clone())
+    }
+
+    /// Stores `embedding` in memory, evicting as needed.
+    fn remember(&self, key: CacheKey, embedding: Vec<f32>, inserted: Instant) {
+        let entry = Entry { embedding, inserted };
+        if entry.bytes() > self.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().expect("cache lock");
+        let mut evicted = 0;
+
+        inner.bytes += entry.bytes();
+        if let Some((old_key, old)) = inner.entries.push(key, entry) {
+            inner.bytes -= old.bytes();
+            if old_key != key {
+                evicted += 1;
+            }
+        }
+        while inner.bytes > self.max_bytes {
+            let Some((_, old)) = inner.entries.pop_lru() else {
+                break;
+            };
+            inner.bytes -= old.bytes();
+            evicted += 1;
+        }
+
+        self.metrics.add_cache_evictions(evicted);
+    }
+}
+
+impl Drop for EmbeddingCache {
+    /// Lets the disk writer finish the embeddings it was handed.
+    fn drop(&mut self) {
+        self.disk_writes = None;
+        if let Some(writer) = self.disk_writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_by_entries_and_bytes() {
+        let metrics = Arc::new(Metrics::default());
+        // Two entries, or fewer than 3 floats' worth of bytes, whichever is hit first
+        let cache = EmbeddingCache::new(2, 11, metrics.clone());
+
+        cache.insert("a", vec![1.0]);
+        cache.insert("b", vec![2.0]);
+        assert_eq!(cache.get("a").await, Some(vec![1.0])); // "b" is now least recently used
+        cache.insert("c", vec![3.0]);
+        assert_eq!(cache.get("b").await, None);
+
+        cache.insert("d", vec![4.0, 4.0]); // 8 bytes, pushes out "a" by entries and "c" by bytes
+        assert_eq!(cache.get("a").await, None);
+        assert_eq!(cache.get("c").await, None);
+        assert_eq!(cache.get("d").await, Some(vec![4.0, 4.0]));
+
+        assert_eq!(metrics.cache_hits.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.cache_misses.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.cache_evictions.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn entries_expire_after_ttl() {
+        let mut cache = EmbeddingCache::new(8, 1024, Arc::default());
+        cache.ttl = Some(Duration::from_secs(60));
+
+        cache.insert("a", vec![1.0]);
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert!(cache.get("a").await.is_some());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(cache.get("a").await.is_none());
+    }
+
+    #[test]
+    fn key_depends_on_model_and_normalization() {
+        let mut cache = EmbeddingCache::new(8, 1024, Arc::default());
+        let plain = cache.key("hello");
+
+        cache.normalize = false;
+        assert_ne!(cache.key("hello"), plain);
+        cache.normalize = true;
+        cache.model = "bge-small".into();
+        assert_ne!(cache.key("hello"), plain);
+    }
+
+    #[tokio::test]
+    async fn restart_warms_memory_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || DiskCache::open(dir.path(), 1 << 20, None).unwrap();
+
+        let cache = EmbeddingCache::new(8, 1024, Arc::default()).with_disk(open());
+        cache.insert("hello", vec![1.0]);
+        drop(cache);
+
+        let metrics = Arc::new(Metrics::default());
+        let cache = EmbeddingCache::new(8, 1024, metrics.clone()).with_disk(open());
+        assert_eq!(cache.recall(cache.key("hello")), Some(vec![1.0]));
+        assert_eq!(cache.get("hello").await, Some(vec![1.0]));
+        assert_eq!(metrics.cache_disk_hits.load(Ordering::Relaxed), 0);
+    }
+}
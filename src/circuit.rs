@@ -0,0 +1,274 @@
+use crate::config::AppConfig;
+use crate::error::ProxyError;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Batches go upstream.
+    Closed,
+    /// Batches fail immediately with `ProxyError::CircuitOpen` until the cool-down is over.
+    Open,
+    /// A limited number of trial batches go upstream; their outcome closes or reopens the circuit.
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        })
+    }
+}
+
+/// When the circuit opens and how it recovers.
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitPolicy {
+    /// Consecutive failed batches that open the circuit.
+    pub max_consecutive_failures: u32,
+    /// Share of failed batches among the last `window` that opens the circuit; `0` disables it.
+    pub max_error_rate: f64,
+    pub window: usize,
+    /// How long the circuit stays open before trial batches are let through.
+    pub cool_down: Duration,
+    /// Concurrent trial batches while half-open.
+    pub trial_calls: u32,
+}
+
+impl Default for CircuitPolicy {
+    fn default() -> Self {
+        Self {
+            max_consecutive_failures: 5,
+            max_error_rate: 0.5,
+            window: 20,
+            cool_down: Duration::from_secs(5),
+            trial_calls: 1,
+        }
+    }
+}
+
+struct Inner {
+    state: CircuitState,
+    opened_at: Instant,
+    consecutive_failures: u32,
+    /// Outcomes of the last `window` batches while closed, `true` for failures.
+    outcomes: VecDeque<bool>,
+    /// Trial batches currently upstream while half-open.
+    trials: u32,
+}
+
+/// Stops sending batches upstream while the upstream is failing, so callers get an immediate
+/// 503 instead of each waiting for a connect timeout. Failures are the errors for which
+/// [`ProxyError::is_upstream_failure`] holds.
+pub struct CircuitBreaker {
+    policy: CircuitPolicy,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitPolicy) -> Self {
+        Self {
+            policy,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                opened_at: Instant::now(),
+                consecutive_failures: 0,
+                outcomes: VecDeque::with_capacity(policy.window),
+                trials: 0,
+            }),
+        }
+    }
+
+    /// Builds the breaker described by `cfg`, or `None` when it is disabled.
+    pub fn from_config(cfg: &AppConfig) -> Option<Arc<Self>> {
+        if cfg.circuit_max_failures == 0 {
+            return None;
+        }
+
+        Some(Arc::new(Self::new(CircuitPolicy {
+            max_consecutive_failures: cfg.circuit_max_failures,
+            max_error_rate: cfg.circuit_max_error_rate,
+            window: cfg.circuit_window,
+            cool_down: Duration::from_millis(cfg.circuit_cool_down_ms),
+            trial_calls: cfg.circuit_trial_calls.max(1),
+        })))
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let mut inner = self.inner.lock().expect("circuit lock");
+        self.cool_down_over(&mut inner, Instant::now());
+
+        inner.state
+    }
+
+    /// Lets a batch through, or fails with `ProxyError::CircuitOpen`. The outcome is reported
+    /// through the returned [`Call`].
+    pub fn allow(self: &Arc<Self>) -> Result<Call, ProxyError> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().expect("circuit lock");
+        self.cool_down_over(&mut inner, now);
+
+        let trial = match inner.state {
+            CircuitState::Closed => false,
+            CircuitState::HalfOpen if inner.trials < self.policy.trial_calls => {
+                inner.trials += 1;
+                true
+            }
+            CircuitState::Open | CircuitState::HalfOpen => {
+                let remaining = (inner.opened_at + self.policy.cool_down).saturating_duration_since(now);
+                return Err(ProxyError::CircuitOpen {
+                    retry_after: remaining.as_secs_f64().ceil().max(1.0) as u64,
+                });
+            }
+        };
+
+        Ok(Call {
+            breaker: self.clone(),
+            trial,
+            done: false,
+        })
+    }
+
+    fn cool_down_over(&self, inner: &mut Inner, now: Instant) {
+        if inner.state == CircuitState::Open && now >= inner.opened_at + self.policy.cool_down {
+            inner.state = CircuitState::HalfOpen;
+            inner.trials = 0;
+            tracing::info!("circuit half-open, sending trial batches");
+        }
+    }
+
+    fn record(&self, trial: bool, failed: bool) {
+        let mut inner = self.inner.lock().expect("circuit lock");
+        if trial {
+            inner.trials = inner.trials.saturating_sub(1);
+        }
+
+        match inner.state {
+            CircuitState::HalfOpen if trial && failed => self.open(&mut inner, "trial batch failed"),
+            CircuitState::HalfOpen if trial => {
+                inner.state = CircuitState::Closed;
+                inner.consecutive_failures = 0;
+                inner.outcomes.clear();
+                tracing::info!("circuit closed");
+            }
+            CircuitState::Closed => {
+                inner.consecutive_failures = if failed { inner.consecutive_failures + 1 } else { 0 };
+                if inner.outcomes.len() == self.policy.window {
+                    inner.outcomes.pop_front();
+                }
+                inner.outcomes.push_back(failed);
+
+                let failures = inner.outcomes.iter().filter(|&&f| f).count();
+                let window_full = inner.outcomes.len() == self.policy.window;
+                if inner.consecutive_failures >= self.policy.max_consecutive_failures {
+                    self.open(&mut inner, "consecutive failures");
+                } else if self.policy.max_error_rate > 0.0
+                    && window_full
+                    && failures as f64 >= self.policy.max_error_rate * self.policy.window as f64
+                {
+                    self.open(&mut inner, "error rate");
+                }
+            }
+            // Late outcomes of batches sent before the circuit opened
+            _ => {}
+        }
+    }
+
+    fn open(&self, inner: &mut Inner, reason: &str) {
+        inner.state = CircuitState::Open;
+        inner.opened_at = Instant::now();
+        tracing::warn!(
+            reason,
+            cool_down_ms = self.policy.cool_down.as_millis() as u64,
+            "circuit open"
+        );
+    }
+}
+
+/// A batch let through by [`CircuitBreaker::allow`]. Dropping it without an outcome, e.g.
+/// because every item of the batch was cancelled, frees its trial slot without counting.
+pub struct Call {
+    breaker: Arc<CircuitBreaker>,
+    trial: bool,
+    done: bool,
+}
+
+impl Call {
+    pub fn record<T>(mut self, result: &Result<T, ProxyError>) {
+        let failed = result.as_ref().is_err_and(ProxyError::is_upstream_failure);
+        self.done = true;
+        self.breaker.record(self.trial, failed);
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        if !self.done && self.trial {
+            let mut inner = self.breaker.inner.lock().expect("circuit lock");
+            inner.trials = inner.trials.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure() -> Result<(), ProxyError> {
+        Err(ProxyError::Request("connection refused".into()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_consecutive_failures_and_closes_after_a_trial() {
+        let breaker = Arc::new(CircuitBreaker::new(CircuitPolicy {
+            max_consecutive_failures: 3,
+            cool_down: Duration::from_secs(2),
+            ..Default::default()
+        }));
+
+        for _ in 0..3 {
+            breaker.allow().unwrap().record(&failure());
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(matches!(
+            breaker.allow(),
+            Err(ProxyError::CircuitOpen { retry_after: 2 })
+        ));
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        let trial = breaker.allow().expect("trial batch");
+        // Only one trial at a time
+        assert!(breaker.allow().is_err());
+        trial.record(&Ok(()));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_on_error_rate_and_reopens_on_failed_trial() {
+        let breaker = Arc::new(CircuitBreaker::new(CircuitPolicy {
+            max_consecutive_failures: 100,
+            max_error_rate: 0.5,
+            window: 4,
+            ..Default::default()
+        }));
+
+        for ok in [true, false, true, false] {
+            let call = breaker.allow().unwrap();
+            if ok {
+                call.record(&Ok(()))
+            } else {
+                call.record(&failure())
+            }
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        tokio::time::advance(CircuitPolicy::default().cool_down).await;
+        breaker.allow().unwrap().record(&failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+}
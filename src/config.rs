@@ -0,0 +1,389 @@
+use crate::queue::BatchMode;
+use crate::upstream::Balance;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Args, CommandFactory, FromArgMatches, Parser};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Settings of the proxy.
+///
+/// Each one is read from, in increasing precedence: its built-in default, the TOML file given with
+/// `--config` (keyed by field name), its environment variable and its command-line flag (the field
+/// name in kebab case, e.g. `--max-batch-size`).
+#[derive(Clone, Debug, Args, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    /// Address the HTTP server listens on.
+    #[arg(long, env = "BIND_ADDR", default_value = "0.0.0.0:3000")]
+    pub bind_addr: String,
+    /// TEI replicas; batches are spread over them according to `balance`.
+    #[arg(long, env = "TEI_URL", value_delimiter = ',', default_value = "http://tei:80")]
+    pub tei_urls: Vec<String>,
+    /// How batches are spread over the replicas: `round-robin`, `least-outstanding` or `p2c`.
+    #[arg(long, env = "UPSTREAM_BALANCE", default_value = "round-robin")]
+    pub balance: Balance,
+    /// How often each replica's `/health` is probed; `0` disables probing.
+    #[arg(long, env = "HEALTH_CHECK_INTERVAL_MS", default_value_t = 5000)]
+    pub health_check_interval_ms: u64,
+    #[arg(long, env = "HEALTH_CHECK_TIMEOUT_MS", default_value_t = 1000)]
+    pub health_check_timeout_ms: u64,
+    /// Longest an embedding call to a replica may take, connecting included.
+    #[arg(long, env = "UPSTREAM_TIMEOUT_MS", default_value_t = 30000)]
+    pub upstream_timeout_ms: u64,
+    /// Longest connecting to a replica may take.
+    #[arg(long, env = "UPSTREAM_CONNECT_TIMEOUT_MS", default_value_t = 2000)]
+    pub upstream_connect_timeout_ms: u64,
+    /// Consecutive failures after which a replica is taken out of rotation.
+    #[arg(long, env = "EJECT_AFTER_FAILURES", default_value_t = 3)]
+    pub eject_after_failures: u32,
+    /// Consecutive successes an ejected replica needs to be fully back in rotation.
+    #[arg(long, env = "REINSTATE_AFTER_SUCCESSES", default_value_t = 2)]
+    pub reinstate_after_successes: u32,
+    /// Consecutive failed batches that open the circuit breaker; `0` disables the breaker.
+    #[arg(long, env = "CIRCUIT_MAX_FAILURES", default_value_t = 5)]
+    pub circuit_max_failures: u32,
+    /// Share of failed batches among the last `circuit_window` that opens the breaker; `0` disables this check.
+    #[arg(long, env = "CIRCUIT_MAX_ERROR_RATE", default_value_t = 0.5)]
+    pub circuit_max_error_rate: f64,
+    #[arg(long, env = "CIRCUIT_WINDOW", default_value_t = 20)]
+    pub circuit_window: usize,
+    /// How long the breaker stays open before trial batches go upstream.
+    #[arg(long, env = "CIRCUIT_COOL_DOWN_MS", default_value_t = 5000)]
+    pub circuit_cool_down_ms: u64,
+    #[arg(long, env = "CIRCUIT_TRIAL_CALLS", default_value_t = 1)]
+    pub circuit_trial_calls: u32,
+    /// Retries of a batch after retryable upstream failures; `0` disables retries.
+    #[arg(long, env = "MAX_RETRIES", default_value_t = 2)]
+    pub max_retries: u32,
+    #[arg(long, env = "RETRY_BASE_DELAY_MS", default_value_t = 20)]
+    pub retry_base_delay_ms: u64,
+    #[arg(long, env = "RETRY_MAX_DELAY_MS", default_value_t = 500)]
+    pub retry_max_delay_ms: u64,
+    /// Retries earned per batch sent, i.e. the share of traffic retries may add.
+    #[arg(long, env = "RETRY_BUDGET_RATIO", default_value_t = 0.2)]
+    pub retry_budget_ratio: f64,
+    /// Retries available before any are earned, and the most that can be saved up.
+    #[arg(long, env = "RETRY_BUDGET_RESERVE", default_value_t = 10.0)]
+    pub retry_budget_reserve: f64,
+    /// Name of the model served by TEI. Part of the cache key, so cached embeddings of another model are never served.
+    #[arg(long, env = "MODEL_ID", default_value = "")]
+    pub model_id: String,
+    /// Whether TEI should L2-normalize embeddings.
+    #[arg(long, env = "NORMALIZE", default_value_t = true, action = ArgAction::Set)]
+    pub normalize: bool,
+    /// Longest an item waits for its batch to fill.
+    #[arg(long, env = "MAX_WAIT_TIME_MS", default_value_t = 8)]
+    pub max_wait_time_ms: u64,
+    /// Picks the wait per batch from the observed load, between `min_wait_time_ms` and `max_wait_time_ms`.
+    /// Only with `BatchMode::Fifo`: the estimate is of the whole stream, not of each length bucket.
+    #[arg(long, env = "ADAPTIVE_WAIT", default_value_t = false, action = ArgAction::Set)]
+    pub adaptive_wait: bool,
+    #[arg(long, env = "MIN_WAIT_TIME_MS", default_value_t = 0)]
+    pub min_wait_time_ms: u64,
+    #[arg(long, env = "MAX_BATCH_SIZE", default_value_t = 32)]
+    pub max_batch_size: usize,
+    /// How long before the tightest request deadline a batch is flushed, to leave time for the upstream call.
+    #[arg(long, env = "DEADLINE_MARGIN_MS", default_value_t = 10)]
+    pub deadline_margin_ms: u64,
+    /// Max wait for `X-Priority: high` requests; `max_wait_time_ms` applies to the rest.
+    #[arg(long, env = "HIGH_PRIORITY_MAX_WAIT_MS", default_value_t = 2)]
+    pub high_priority_max_wait_ms: u64,
+    /// Batch slots kept for low-priority items while high-priority traffic is saturating.
+    #[arg(long, env = "LOW_PRIORITY_MIN_SLOTS", default_value_t = 4)]
+    pub low_priority_min_slots: usize,
+    /// Token budget per upstream flush; should not exceed TEI's `--max-batch-tokens`.
+    #[arg(long, env = "MAX_BATCH_TOKENS", default_value_t = 16384)]
+    pub max_batch_tokens: usize,
+    /// Optional `tokenizer.json` used for token estimates (requires the `tokenizer` feature).
+    #[arg(long, env = "TOKENIZER_PATH")]
+    pub tokenizer_path: Option<String>,
+    /// `fifo` or `bucketed`.
+    #[arg(long, env = "BATCH_MODE", default_value = "fifo")]
+    pub batch_mode: BatchMode,
+    /// Inclusive token upper bounds of the length buckets used by `BatchMode::Bucketed`.
+    #[arg(long, env = "BUCKET_BOUNDARIES", value_delimiter = ',', default_values_t = [32, 128, 512])]
+    pub bucket_boundaries: Vec<usize>,
+    /// Concurrent upstream calls per TEI replica; the initial limit with `adaptive_concurrency`.
+    #[arg(long, env = "BATCH_CONCURRENCY", default_value_t = 4)]
+    pub batch_concurrency: usize,
+    /// Adjusts the concurrency of each replica to its observed latency and errors, within the bounds below.
+    #[arg(long, env = "ADAPTIVE_CONCURRENCY", default_value_t = true, action = ArgAction::Set)]
+    pub adaptive_concurrency: bool,
+    #[arg(long, env = "MIN_BATCH_CONCURRENCY", default_value_t = 1)]
+    pub min_batch_concurrency: usize,
+    #[arg(long, env = "MAX_BATCH_CONCURRENCY", default_value_t = 32)]
+    pub max_batch_concurrency: usize,
+    /// Capacity of each queue lane.
+    #[arg(long, env = "QUEUE_CAP", default_value_t = 2048)]
+    pub queue_cap: usize,
+    /// Scheduling weight per tenant, as `name=weight,...`; unlisted tenants weigh 1.
+    #[arg(long, env = "TENANT_WEIGHTS", value_parser = parse_map::<u32>, default_value = "")]
+    pub tenant_weights: HashMap<String, u32>,
+    /// Max items a tenant may have queued or in flight; `0` means unlimited.
+    #[arg(long, env = "TENANT_MAX_QUEUED", default_value_t = 0)]
+    pub tenant_max_queued: usize,
+    /// Per-tenant overrides of `tenant_max_queued`, as `name=limit,...`.
+    #[arg(long, env = "TENANT_QUEUE_LIMITS", value_parser = parse_map::<usize>, default_value = "")]
+    pub tenant_queue_limits: HashMap<String, usize>,
+    /// How long a request may wait for room in a full queue before it is rejected with 429.
+    #[arg(long, env = "ENQUEUE_TIMEOUT_MS", default_value_t = 75)]
+    pub enqueue_timeout_ms: u64,
+    /// Share of `queue_cap` above which `/ready` fails.
+    #[arg(long, env = "READY_QUEUE_HIGH_WATER", default_value_t = 0.9)]
+    pub ready_queue_high_water: f64,
+    /// How recently some replica must have answered for `/ready` to pass; `0` skips the check.
+    #[arg(long, env = "READY_UPSTREAM_MAX_AGE_MS", default_value_t = 15_000)]
+    pub ready_upstream_max_age_ms: u64,
+    /// How long a shutdown waits for queued requests and in-flight batches before exiting anyway.
+    #[arg(long, env = "SHUTDOWN_GRACE_MS", default_value_t = 20_000)]
+    pub shutdown_grace_ms: u64,
+    /// Max embeddings kept in the in-memory cache; `0` disables it.
+    #[arg(long, env = "CACHE_MAX_ENTRIES", default_value_t = 10_000)]
+    pub cache_max_entries: usize,
+    /// Max total size of the cached embeddings in bytes; `0` disables the cache.
+    #[arg(long, env = "CACHE_MAX_BYTES", default_value_t = 64 << 20)]
+    pub cache_max_bytes: usize,
+    /// How long a cached embedding stays valid; `0` keeps it until evicted.
+    #[arg(long, env = "CACHE_TTL_SECS", default_value_t = 0)]
+    pub cache_ttl_secs: u64,
+    /// Directory of the persistent cache behind the memory cache; unset keeps embeddings in memory only.
+    #[arg(long, env = "CACHE_DIR")]
+    pub cache_dir: Option<String>,
+    /// Size cap of the persistent cache's log in bytes.
+    #[arg(long, env = "CACHE_DISK_MAX_BYTES", default_value_t = 1 << 30)]
+    pub cache_disk_max_bytes: u64,
+    /// OTLP/HTTP collector spans are exported to, e.g. `http://otel-collector:4318`; unset disables export.
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` of the exported spans.
+    #[arg(long, env = "OTEL_SERVICE_NAME", default_value = "auto-batching-proxy")]
+    pub service_name: String,
+}
+
+impl Default for AppConfig {
+    /// The built-in defaults, ignoring the environment, config file and flags.
+    fn default() -> Self {
+        let matches = Self::augment_args(clap::Command::new("defaults"))
+            .mut_args(|arg| arg.env(None))
+            .get_matches_from(["defaults"]);
+        Self::from_arg_matches(&matches).expect("built-in defaults are valid")
+    }
+}
+
+/// Command line of the proxy.
+#[derive(Parser)]
+#[command(version, about = "Batches embedding requests in front of Text Embeddings Inference")]
+struct Cli {
+    /// TOML file with any of the settings; environment variables and flags take precedence over it.
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// Print the effective configuration as TOML and exit.
+    #[arg(long)]
+    print_config: bool,
+    #[command(flatten)]
+    app: AppConfig,
+}
+
+impl AppConfig {
+    /// Reads the configuration from the command line, the environment and the config file. Exits
+    /// with a usage error if any setting is invalid, and after printing it with `--print-config`.
+    pub fn load() -> Self {
+        let (cfg, print) = parse(std::env::args_os()).unwrap_or_else(|e| e.exit());
+        if print {
+            print!("{}", toml::to_string(&cfg).expect("config serializes to TOML"));
+            std::process::exit(0);
+        }
+
+        cfg
+    }
+
+    /// Checks the settings against each other and their valid ranges.
+    pub fn validate(&self) -> Result<(), String> {
+        let checks = [
+            (self.tei_urls.is_empty(), "tei_urls must list at least one replica"),
+            (
+                !self
+                    .tei_urls
+                    .iter()
+                    .all(|u| u.starts_with("http://") || u.starts_with("https://")),
+                "tei_urls must be http:// or https:// URLs",
+            ),
+            (self.max_batch_size == 0, "max_batch_size must be at least 1"),
+            (self.max_batch_tokens == 0, "max_batch_tokens must be at least 1"),
+            (self.queue_cap == 0, "queue_cap must be at least 1"),
+            (self.batch_concurrency == 0, "batch_concurrency must be at least 1"),
+            (
+                self.health_check_timeout_ms == 0,
+                "health_check_timeout_ms must be at least 1",
+            ),
+            (
+                self.upstream_timeout_ms == 0 || self.upstream_connect_timeout_ms == 0,
+                "upstream_timeout_ms and upstream_connect_timeout_ms must be at least 1",
+            ),
+            (
+                self.min_batch_concurrency == 0 || self.min_batch_concurrency > self.max_batch_concurrency,
+                "min_batch_concurrency must be between 1 and max_batch_concurrency",
+            ),
+            (
+                self.adaptive_wait && self.batch_mode == BatchMode::Bucketed,
+                "adaptive_wait requires batch_mode = fifo",
+            ),
+            (
+                self.min_wait_time_ms > self.max_wait_time_ms,
+                "min_wait_time_ms must not exceed max_wait_time_ms",
+            ),
+            (
+                self.retry_base_delay_ms > self.retry_max_delay_ms,
+                "retry_base_delay_ms must not exceed retry_max_delay_ms",
+            ),
+            (
+                !(0.0..=1.0).contains(&self.circuit_max_error_rate),
+                "circuit_max_error_rate must be between 0 and 1",
+            ),
+            (self.circuit_window == 0, "circuit_window must be at least 1"),
+            (
+                !(0.0..=1.0).contains(&self.ready_queue_high_water),
+                "ready_queue_high_water must be between 0 and 1",
+            ),
+            (
+                !(self.retry_budget_ratio >= 0.0 && self.retry_budget_reserve >= 0.0),
+                "retry_budget_ratio and retry_budget_reserve must not be negative",
+            ),
+            (
+                self.tenant_weights.values().any(|&w| w == 0),
+                "tenant_weights must be at least 1",
+            ),
+        ];
+
+        match checks.into_iter().find(|(failed, _)| *failed) {
+            Some((_, message)) => Err(message.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parses `args` and layers the config file under them, returning the configuration and whether
+/// `--print-config` was given.
+fn parse<I, T>(args: I) -> Result<(AppConfig, bool), clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let matches = Cli::command().try_get_matches_from(args)?;
+    let cli = Cli::from_arg_matches(&matches)?;
+
+    let mut cfg = cli.app;
+    if let Some(path) = &cli.config {
+        cfg = with_file(path, &matches, cfg).map_err(invalid)?;
+    }
+    cfg.validate().map_err(invalid)?;
+
+    Ok((cfg, cli.print_config))
+}
+
+/// `cfg` with every setting neither the environment nor the command line gave taken from the
+/// file at `path`.
+fn with_file(path: &Path, matches: &ArgMatches, cfg: AppConfig) -> Result<AppConfig, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+    let file: AppConfig = toml::from_str(&text).map_err(|e| format!("invalid config file {}: {e}", path.display()))?;
+
+    let mut merged = toml::Table::try_from(&file).expect("config serializes to TOML");
+    for (key, value) in toml::Table::try_from(&cfg).expect("config serializes to TOML") {
+        if matches!(
+            matches.value_source(&key),
+            Some(ValueSource::EnvVariable | ValueSource::CommandLine)
+        ) {
+            merged.insert(key, value);
+        }
+    }
+
+    Ok(merged.try_into().expect("merged config deserializes"))
+}
+
+fn invalid(message: String) -> clap::Error {
+    Cli::command().error(ErrorKind::ValueValidation, message)
+}
+
+/// Parses `name=value,...` pairs.
+fn parse_map<T: FromStr>(s: &str) -> Result<HashMap<String, T>, String> {
+    s.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (k, v) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected `name=value`, got `{pair}`"))?;
+            let v = v.trim().parse().map_err(|_| format!("invalid value in `{pair}`"))?;
+            Ok((k.trim().to_string(), v))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn config_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    fn parse_args(args: &[&str]) -> Result<AppConfig, clap::Error> {
+        parse(["auto-batching-proxy"].iter().chain(args)).map(|(cfg, _)| cfg)
+    }
+
+    #[test]
+    fn flags_override_the_file_which_overrides_defaults() {
+        let file =
+            config_file("max_batch_size = 64\nqueue_cap = 100\nbalance = \"p2c\"\nbucket_boundaries = [16, 64]\n");
+        let path = file.path().to_str().unwrap();
+
+        let cfg = parse_args(&["--config", path, "--queue-cap", "10", "--tenant-weights", "a=2,b=1"]).unwrap();
+        assert_eq!(cfg.max_batch_size, 64);
+        assert_eq!(cfg.queue_cap, 10);
+        assert_eq!(cfg.balance, Balance::PowerOfTwo);
+        assert_eq!(cfg.bucket_boundaries, [16, 64]);
+        assert_eq!(cfg.tenant_weights["a"], 2);
+        assert_eq!(cfg.max_batch_tokens, AppConfig::default().max_batch_tokens);
+
+        // What --print-config shows reads back as the same configuration
+        let printed = toml::to_string(&cfg).unwrap();
+        let reread = parse_args(&["--config", config_file(&printed).path().to_str().unwrap()]).unwrap();
+        assert_eq!(reread.queue_cap, 10);
+        assert_eq!(reread.tenant_weights, cfg.tenant_weights);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let err = parse_args(&["--max-batch-size", "abc"]).unwrap_err();
+        assert!(err.to_string().contains("--max-batch-size"), "{err}");
+
+        let file = config_file("max_batch_size = \"abc\"\n");
+        let err = parse_args(&["--config", file.path().to_str().unwrap()]).unwrap_err();
+        assert!(err.to_string().contains("max_batch_size"), "{err}");
+
+        let file = config_file("max_batch_sise = 8\n");
+        let err = parse_args(&["--config", file.path().to_str().unwrap()]).unwrap_err();
+        assert!(err.to_string().contains("unknown field `max_batch_sise`"), "{err}");
+
+        let err = parse_args(&["--min-wait-time-ms", "20", "--max-wait-time-ms", "10"]).unwrap_err();
+        assert!(err.to_string().contains("min_wait_time_ms"), "{err}");
+
+        let err = parse_args(&["--adaptive-wait", "true", "--batch-mode", "bucketed"]).unwrap_err();
+        assert!(err.to_string().contains("adaptive_wait"), "{err}");
+
+        let err = parse_args(&["--health-check-timeout-ms", "0"]).unwrap_err();
+        assert!(err.to_string().contains("health_check_timeout_ms"), "{err}");
+
+        let err = parse_args(&["--upstream-connect-timeout-ms", "0"]).unwrap_err();
+        assert!(err.to_string().contains("upstream_connect_timeout_ms"), "{err}");
+
+        let err = parse_args(&["--tenant-weights", "a=2,b"]).unwrap_err();
+        assert!(err.to_string().contains("name=value"), "{err}");
+    }
+}
@@ -0,0 +1,390 @@
+use crate::cache::CacheKey;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Written at the start of both files; files without it are discarded on open.
+const MAGIC: &[u8; 8] = b"ABPEMB01";
+const LOG_FILE: &str = "embeddings.log";
+const INDEX_FILE: &str = "embeddings.idx";
+/// Key, write time and dimension count in front of every embedding in the log.
+const RECORD_HEADER: u64 = 16 + 8 + 4;
+/// Key, log offset, write time and dimension count.
+const INDEX_ENTRY: usize = 16 + 8 + 8 + 4;
+
+/// Where an embedding lives in the log.
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    offset: u64,
+    /// Seconds since the Unix epoch.
+    written_at: u64,
+    dims: u32,
+}
+
+impl Slot {
+    fn record_len(&self) -> u64 {
+        RECORD_HEADER + self.dims as u64 * 4
+    }
+
+    fn age(&self, now: u64) -> Duration {
+        Duration::from_secs(now.saturating_sub(self.written_at))
+    }
+}
+
+struct Store {
+    log: File,
+    index: File,
+    /// Length of the log, i.e. where the next record goes.
+    len: u64,
+    slots: HashMap<CacheKey, Slot>,
+}
+
+/// Embeddings persisted under a directory, so they survive restarts.
+///
+/// Records are appended to `embeddings.log`, and their position to `embeddings.idx`, which is
+/// all that is read on startup. Once the log would outgrow its cap it is compacted: the most
+/// recently written live records are copied to a fresh log filling half the cap, and the rest
+/// are dropped. A crash mid-write at worst loses the records whose index entry did not make it.
+///
+/// Every method blocks on file I/O, so callers on an async runtime go through
+/// `spawn_blocking` or a thread of their own. Writes are serialized by a lock of their own, and
+/// compaction copies records without holding the store lock, so reads go on meanwhile.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Option<Duration>,
+    store: Mutex<Store>,
+    /// Held by `put` throughout, compaction included.
+    writing: Mutex<()>,
+}
+
+impl DiskCache {
+    pub fn open(dir: impl AsRef<Path>, max_bytes: u64, ttl: Option<Duration>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let store = Store::open(&dir.join(LOG_FILE), &dir.join(INDEX_FILE))?;
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            ttl,
+            store: Mutex::new(store),
+            writing: Mutex::default(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.lock().expect("disk cache lock").slots.len()
+    }
+
+    pub fn get(&self, key: CacheKey) -> io::Result<Option<Vec<f32>>> {
+        let mut store = self.store.lock().expect("disk cache lock");
+        let Some(slot) = store.slots.get(&key).copied() else {
+            return Ok(None);
+        };
+        if self.is_expired(&slot, unix_now()) {
+            return Ok(None);
+        }
+
+        let embedding = store.read(key, slot);
+        if embedding.is_err() {
+            // Unreadable records are forgotten, so the next flush writes them again
+            store.slots.remove(&key);
+        }
+
+        embedding.map(Some)
+    }
+
+    /// Persists `embedding` unless a live copy is already stored.
+    pub fn put(&self, key: CacheKey, embedding: &[f32]) -> io::Result<()> {
+        let now = unix_now();
+        let _writing = self.writing.lock().expect("disk cache write lock");
+        let mut slot = Slot {
+            offset: 0,
+            written_at: now,
+            dims: embedding.len() as u32,
+        };
+        if MAGIC.len() as u64 + slot.record_len() > self.max_bytes {
+            return Ok(());
+        }
+
+        let full = {
+            let store = self.store.lock().expect("disk cache lock");
+            if store.slots.get(&key).is_some_and(|slot| !self.is_expired(slot, now)) {
+                return Ok(());
+            }
+            store.len + slot.record_len() > self.max_bytes
+        };
+        if full {
+            self.compact(now)?;
+        }
+
+        let mut store = self.store.lock().expect("disk cache lock");
+        slot.offset = store.len;
+        store.append(key, slot, embedding)
+    }
+
+    /// The most recently written live embeddings, oldest first, within `max_entries` and
+    /// `max_bytes` of embedding data, together with their age. Used to warm the memory cache.
+    pub fn recent(&self, max_entries: usize, max_bytes: usize) -> Vec<(CacheKey, Vec<f32>, Duration)> {
+        let now = unix_now();
+        let mut store = self.store.lock().expect("disk cache lock");
+        let mut bytes = 0;
+        let mut recent = Vec::new();
+
+        for (key, slot) in self.live(&store, now) {
+            bytes += slot.dims as usize * 4;
+            if recent.len() == max_entries || bytes > max_bytes {
+                break;
+            }
+            recent.push((key, slot));
+        }
+
+        recent
+            .into_iter()
+            .rev()
+            .filter_map(|(key, slot)| Some((key, store.read(key, slot).ok()?, slot.age(now))))
+            .collect()
+    }
+
+    fn is_expired(&self, slot: &Slot, now: u64) -> bool {
+        self.ttl.is_some_and(|ttl| slot.age(now) >= ttl)
+    }
+
+    /// Unexpired slots, most recently written first.
+    fn live(&self, store: &Store, now: u64) -> Vec<(CacheKey, Slot)> {
+        let mut live: Vec<_> = store
+            .slots
+            .iter()
+            .filter(|(_, slot)| !self.is_expired(slot, now))
+            .map(|(key, slot)| (*key, *slot))
+            .collect();
+        live.sort_by_key(|(_, slot)| Reverse(slot.offset));
+
+        live
+    }
+
+    /// Rewrites the log with the most recent live records filling up to half the cap, so the
+    /// next writes do not immediately trigger another compaction. Called by `put` only, so
+    /// the log does not change underneath; the store lock is only taken to pick the records
+    /// and to swap the files.
+    fn compact(&self, now: u64) -> io::Result<()> {
+        let budget = self.max_bytes / 2;
+        let mut used = MAGIC.len() as u64;
+        let mut kept = Vec::new();
+        let before = {
+            let store = self.store.lock().expect("disk cache lock");
+            for (key, slot) in self.live(&store, now) {
+                used += slot.record_len();
+                if used > budget {
+                    break;
+                }
+                kept.push((key, slot));
+            }
+            store.slots.len()
+        };
+
+        let log_path = self.dir.join(LOG_FILE);
+        let index_path = self.dir.join(INDEX_FILE);
+        let log_tmp = self.dir.join(format!("{LOG_FILE}.tmp"));
+        let index_tmp = self.dir.join(format!("{INDEX_FILE}.tmp"));
+        let mut fresh = Store::create(&log_tmp, &index_tmp)?;
+        let mut log = File::open(&log_path)?;
+
+        for (key, mut slot) in kept.into_iter().rev() {
+            let Ok(embedding) = read_record(&mut log, key, slot) else {
+                continue;
+            };
+            slot.offset = fresh.len;
+            fresh.append(key, slot, &embedding)?;
+        }
+        fresh.log.sync_all()?;
+        fresh.index.sync_all()?;
+
+        // Should we stop between the two renames, the old index points into the new log,
+        // which `read` detects by the key at the start of every record
+        let mut store = self.store.lock().expect("disk cache lock");
+        fs::rename(&log_tmp, &log_path)?;
+        fs::rename(&index_tmp, &index_path)?;
+
+        tracing::info!(
+            before,
+            after = fresh.slots.len(),
+            bytes = fresh.len,
+            "disk cache compacted"
+        );
+        *store = fresh;
+
+        Ok(())
+    }
+}
+
+impl Store {
+    fn open(log_path: &Path, index_path: &Path) -> io::Result<Self> {
+        let mut log = open_append(log_path)?;
+        let mut index = open_append(index_path)?;
+        if !has_magic(&mut log)? || !has_magic(&mut index)? {
+            // New, or written by an incompatible version
+            return Self::create(log_path, index_path);
+        }
+
+        let len = log.metadata()?.len();
+        let mut entries = Vec::new();
+        index.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        index.read_to_end(&mut entries)?;
+
+        let mut slots = HashMap::new();
+        for entry in entries.chunks_exact(INDEX_ENTRY) {
+            let key = CacheKey::from_bytes(entry[..16].try_into().unwrap());
+            let slot = Slot {
+                offset: u64::from_le_bytes(entry[16..24].try_into().unwrap()),
+                written_at: u64::from_le_bytes(entry[24..32].try_into().unwrap()),
+                dims: u32::from_le_bytes(entry[32..36].try_into().unwrap()),
+            };
+            // Records that never fully reached the log are skipped
+            if slot.offset + slot.record_len() <= len {
+                slots.insert(key, slot);
+            }
+        }
+
+        Ok(Self { log, index, len, slots })
+    }
+
+    /// Starts empty files at the given paths, replacing any existing ones.
+    fn create(log_path: &Path, index_path: &Path) -> io::Result<Self> {
+        let mut log = open_append(log_path)?;
+        let mut index = open_append(index_path)?;
+        for file in [&mut log, &mut index] {
+            file.set_len(0)?;
+            file.write_all(MAGIC)?;
+        }
+
+        Ok(Self {
+            log,
+            index,
+            len: MAGIC.len() as u64,
+            slots: HashMap::new(),
+        })
+    }
+
+    fn append(&mut self, key: CacheKey, slot: Slot, embedding: &[f32]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(slot.record_len() as usize);
+        record.extend_from_slice(&key.to_bytes());
+        record.extend_from_slice(&slot.written_at.to_le_bytes());
+        record.extend_from_slice(&slot.dims.to_le_bytes());
+        for v in embedding {
+            record.extend_from_slice(&v.to_le_bytes());
+        }
+        self.log.write_all(&record)?;
+        self.len += record.len() as u64;
+
+        let mut entry = Vec::with_capacity(INDEX_ENTRY);
+        entry.extend_from_slice(&key.to_bytes());
+        entry.extend_from_slice(&slot.offset.to_le_bytes());
+        entry.extend_from_slice(&slot.written_at.to_le_bytes());
+        entry.extend_from_slice(&slot.dims.to_le_bytes());
+        self.index.write_all(&entry)?;
+
+        self.slots.insert(key, slot);
+        Ok(())
+    }
+
+    fn read(&mut self, key: CacheKey, slot: Slot) -> io::Result<Vec<f32>> {
+        read_record(&mut self.log, key, slot)
+    }
+}
+
+/// Reads the embedding of `key` at `slot` from `log`.
+fn read_record(log: &mut File, key: CacheKey, slot: Slot) -> io::Result<Vec<f32>> {
+    let mut record = vec![0; slot.record_len() as usize];
+    log.seek(SeekFrom::Start(slot.offset))?;
+    log.read_exact(&mut record)?;
+
+    if record[..16] != key.to_bytes() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "index does not match log"));
+    }
+
+    Ok(record[RECORD_HEADER as usize..]
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect())
+}
+
+/// Opens `path` for reading and appending. Reads seek freely; writes always go to the end.
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).append(true).create(true).open(path)
+}
+
+fn has_magic(file: &mut File) -> io::Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    file.seek(SeekFrom::Start(0))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u128) -> CacheKey {
+        CacheKey::from_bytes(n.to_le_bytes())
+    }
+
+    #[test]
+    fn embeddings_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiskCache::open(dir.path(), 1 << 20, None).unwrap();
+        cache.put(key(1), &[1.0, 2.0]).unwrap();
+        cache.put(key(2), &[3.0]).unwrap();
+        drop(cache);
+
+        // A torn index entry from a crash mid-write is ignored
+        let mut index = open_append(&dir.path().join(INDEX_FILE)).unwrap();
+        index.write_all(&[0xff; 7]).unwrap();
+
+        let cache = DiskCache::open(dir.path(), 1 << 20, None).unwrap();
+        assert_eq!(cache.get(key(1)).unwrap(), Some(vec![1.0, 2.0]));
+        assert_eq!(cache.get(key(2)).unwrap(), Some(vec![3.0]));
+        assert_eq!(cache.get(key(3)).unwrap(), None);
+
+        let recent = cache.recent(1, usize::MAX);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].0, key(2));
+    }
+
+    #[test]
+    fn compaction_keeps_the_newest_records_within_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        // Each record is 28 + 4 bytes; the cap fits the magic plus 5 of them
+        let max_bytes = MAGIC.len() as u64 + 5 * 32;
+        let cache = DiskCache::open(dir.path(), max_bytes, None).unwrap();
+        for n in 0..6 {
+            cache.put(key(n), &[n as f32]).unwrap();
+        }
+
+        // The sixth write compacted down to half the cap (the 2 newest records), then appended
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(key(2)).unwrap(), None);
+        assert_eq!(cache.get(key(3)).unwrap(), Some(vec![3.0]));
+        assert_eq!(cache.get(key(5)).unwrap(), Some(vec![5.0]));
+        assert!(fs::metadata(dir.path().join(LOG_FILE)).unwrap().len() <= max_bytes);
+
+        let reopened = DiskCache::open(dir.path(), max_bytes, None).unwrap();
+        assert_eq!(reopened.len(), 3);
+        assert_eq!(reopened.get(key(5)).unwrap(), Some(vec![5.0]));
+    }
+}
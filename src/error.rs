@@ -1,5 +1,6 @@
-use actix_web::ResponseError;
 use actix_web::http::StatusCode;
+use actix_web::http::header::{ContentType, RETRY_AFTER};
+use actix_web::{HttpResponse, ResponseError};
 use thiserror::Error;
 
 #[derive(Debug, Error, Clone)]
@@ -7,9 +8,21 @@ pub enum ProxyError {
     #[error("batcher unavailable")]
     BatcherUnavailable,
 
+    #[error("queue full, retry in {retry_after}s")]
+    QueueFull { retry_after: u64 },
+
+    #[error("request deadline exceeded")]
+    DeadlineExceeded,
+
+    #[error("upstream circuit open, retry in {retry_after}s")]
+    CircuitOpen { retry_after: u64 },
+
     #[error("service shutting down")]
     ServiceShutdown,
 
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
     #[error("upstream {code}: {body}")]
     Upstream { code: u16, body: String },
 
@@ -27,13 +40,83 @@ impl ResponseError for ProxyError {
     fn status_code(&self) -> StatusCode {
         match self {
             ProxyError::BatcherUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::QueueFull { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ProxyError::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::CircuitOpen { .. } => StatusCode::SERVICE_UNAVAILABLE,
             ProxyError::ServiceShutdown => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
             ProxyError::Upstream { code, .. } => StatusCode::from_u16(*code).unwrap_or(StatusCode::BAD_GATEWAY),
             ProxyError::Request(_) => StatusCode::BAD_GATEWAY,
             ProxyError::CountMismatch { .. } => StatusCode::BAD_GATEWAY,
             ProxyError::Receiver(_) => StatusCode::BAD_GATEWAY,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        res.insert_header(ContentType::plaintext());
+
+        if let Some(secs) = self.retry_after() {
+            res.insert_header((RETRY_AFTER, secs.to_string()));
+        }
+
+        res.body(self.to_string())
+    }
+}
+
+impl ProxyError {
+    /// Name of the variant, used as the `kind` label of the error metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProxyError::BatcherUnavailable => "batcher_unavailable",
+            ProxyError::QueueFull { .. } => "queue_full",
+            ProxyError::DeadlineExceeded => "deadline_exceeded",
+            ProxyError::CircuitOpen { .. } => "circuit_open",
+            ProxyError::ServiceShutdown => "service_shutdown",
+            ProxyError::InvalidRequest(_) => "invalid_request",
+            ProxyError::Upstream { .. } => "upstream",
+            ProxyError::Request(_) => "request",
+            ProxyError::CountMismatch { .. } => "count_mismatch",
+            ProxyError::Receiver(_) => "receiver",
+        }
+    }
+
+    /// Seconds a client should back off before retrying, sent as `Retry-After`.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            ProxyError::QueueFull { retry_after } | ProxyError::CircuitOpen { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// Worth sending again: the upstream could not be reached, was overloaded (TEI answers 429
+    /// when its queue is full) or a gateway in front of it failed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ProxyError::Request(_) => true,
+            ProxyError::Upstream { code, .. } => matches!(code, 429 | 502 | 503 | 504),
+            _ => false,
+        }
+    }
+
+    /// The upstream itself is at fault: it could not be reached or answered with a 5xx.
+    pub fn is_upstream_failure(&self) -> bool {
+        match self {
+            ProxyError::Request(_) => true,
+            ProxyError::Upstream { code, .. } => *code >= 500,
+            _ => false,
+        }
+    }
+
+    /// TEI refused the batch as a whole, most likely because of one of its inputs (too long,
+    /// malformed), or answered with the wrong number of embeddings.
+    pub fn is_batch_rejection(&self) -> bool {
+        match self {
+            ProxyError::CountMismatch { .. } => true,
+            ProxyError::Upstream { code, .. } => matches!(code, 413 | 422),
+            _ => false,
+        }
+    }
 }
 
 impl From<reqwest::Error> for ProxyError {
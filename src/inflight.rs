@@ -0,0 +1,141 @@
+use crate::batcher::{BatchItem, screen};
+use crate::error::ProxyError;
+use crate::metrics::Metrics;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+use tokio::time::Instant;
+
+/// Inputs that are queued for or being embedded upstream, with every item waiting on each.
+///
+/// Each unique input is owned by the flush that registered it first: only that flush sends it
+/// upstream and answers its waiters. Identical items of later batches join the existing entry
+/// instead of being sent again, until the owner completes it.
+#[derive(Default)]
+pub struct InFlight {
+    waiters: Mutex<HashMap<String, Vec<BatchItem>>>,
+}
+
+impl InFlight {
+    /// Registers the items of `batch` and returns the inputs this flush owns, in batch order.
+    /// Items whose input is already in flight, here or in another flush, only wait for it.
+    pub fn join(&self, batch: Vec<BatchItem>, metrics: &Metrics) -> Vec<String> {
+        let mut waiters = self.waiters.lock().expect("in-flight lock");
+        let mut owned = Vec::with_capacity(batch.len());
+        let mut coalesced = 0;
+
+        for item in batch {
+            match waiters.entry(item.input.clone()) {
+                Entry::Occupied(mut e) => {
+                    coalesced += 1;
+                    e.get_mut().push(item);
+                }
+                Entry::Vacant(e) => {
+                    owned.push(e.key().clone());
+                    e.insert(vec![item]);
+                }
+            }
+        }
+
+        metrics.add_coalesced(coalesced);
+        owned
+    }
+
+    /// Screens the waiters of `inputs` (see [`screen`]) and returns the inputs somebody still
+    /// waits for. The others are forgotten, so a later request for them starts a new flight.
+    pub fn prune(&self, inputs: Vec<String>, metrics: &Metrics) -> Vec<String> {
+        let now = Instant::now();
+        let mut waiters = self.waiters.lock().expect("in-flight lock");
+
+        inputs
+            .into_iter()
+            .filter(|input| {
+                let Some(items) = waiters.get_mut(input) else {
+                    return false;
+                };
+                *items = std::mem::take(items)
+                    .into_iter()
+                    .filter_map(|item| screen(item, now, metrics))
+                    .collect();
+
+                if items.is_empty() {
+                    waiters.remove(input);
+                    return false;
+                }
+                true
+            })
+            .collect()
+    }
+
+    /// The last deadline of any item waiting for `inputs`, or `None` if one of them has none.
+    pub fn latest_deadline(&self, inputs: &[String]) -> Option<Instant> {
+        let waiters = self.waiters.lock().expect("in-flight lock");
+
+        inputs
+            .iter()
+            .flat_map(|input| waiters.get(input).into_iter().flatten())
+            .map(|item| item.deadline)
+            .try_fold(None, |latest: Option<Instant>, deadline| {
+                Some(latest.max(Some(deadline?)))
+            })
+            .flatten()
+    }
+
+    /// Answers every item waiting for `input` and ends its flight.
+    pub fn complete(&self, input: &str, result: Result<Vec<f32>, ProxyError>) {
+        let items = self.waiters.lock().expect("in-flight lock").remove(input);
+
+        for item in items.into_iter().flatten() {
+            let _ = item.resp.send(result.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn identical_inputs_share_one_flight() {
+        let in_flight = InFlight::default();
+        let metrics = Metrics::default();
+
+        let (a1, rx_a1) = BatchItem::test("a", 1);
+        let (a2, rx_a2) = BatchItem::test("a", 1);
+        let (b, rx_b) = BatchItem::test("b", 1);
+        assert_eq!(in_flight.join(vec![a1, a2, b], &metrics), ["a", "b"]);
+
+        // A later batch joins the flight that is still running
+        let (a3, rx_a3) = BatchItem::test("a", 1);
+        assert!(in_flight.join(vec![a3], &metrics).is_empty());
+        assert_eq!(metrics.coalesced.load(std::sync::atomic::Ordering::Relaxed), 2);
+
+        in_flight.complete("a", Ok(vec![1.0]));
+        in_flight.complete("b", Ok(vec![2.0]));
+        for rx in [rx_a1, rx_a2, rx_a3] {
+            assert_eq!(rx.await.unwrap().unwrap(), vec![1.0]);
+        }
+        assert_eq!(rx_b.await.unwrap().unwrap(), vec![2.0]);
+
+        // Once completed, the input is sent again
+        let (a4, _rx_a4) = BatchItem::test("a", 1);
+        assert_eq!(in_flight.join(vec![a4], &metrics), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn prune_drops_inputs_nobody_waits_for() {
+        let in_flight = InFlight::default();
+        let metrics = Metrics::default();
+
+        let (gone, rx_gone) = BatchItem::test("gone", 1);
+        let (kept1, rx_kept1) = BatchItem::test("kept", 1);
+        let (kept2, _rx_kept2) = BatchItem::test("kept", 1);
+        let owned = in_flight.join(vec![gone, kept1, kept2], &metrics);
+        drop(rx_gone);
+        drop(rx_kept1);
+
+        // One of the two "kept" callers is still there, so the input is still sent
+        assert_eq!(in_flight.prune(owned, &metrics), ["kept"]);
+        assert_eq!(metrics.cancelled.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::error::ProxyError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Latency may rise this far above its long-term average before the limit shrinks.
+const TOLERANCE: f64 = 1.5;
+/// Share of a new estimate blended into the limit per batch.
+const SMOOTHING: f64 = 0.2;
+/// Factor the limit is cut by after a failed or throttled batch.
+const BACKOFF: f64 = 0.9;
+
+/// Initial value and bounds of a replica's concurrency limit.
+#[derive(Clone, Copy, Debug)]
+pub struct ConcurrencyLimits {
+    pub initial: usize,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl ConcurrencyLimits {
+    /// A limit that never moves from `limit`.
+    pub fn fixed(limit: usize) -> Self {
+        Self {
+            initial: limit,
+            min: limit,
+            max: limit,
+        }
+    }
+}
+
+struct State {
+    limit: f64,
+    in_flight: usize,
+    closed: bool,
+    /// Moving averages of batch latency in microseconds over the last few and the last many
+    /// batches; `0` until the first one.
+    short_rtt: f64,
+    long_rtt: f64,
+}
+
+/// Bounds the concurrent batches sent to one TEI replica, adjusting the bound to what the
+/// replica sustains in the style of Netflix's gradient limiter.
+///
+/// The limit follows the ratio of long-term to recent latency. While batches come back about as
+/// fast as usual it grows by roughly its square root, leaving room for a small queue on the
+/// GPU; once recent latency rises past `TOLERANCE` times the long-term average, batches are
+/// queueing upstream and it shrinks in proportion. Failed or throttled batches cut it by a
+/// tenth. It grows only while at least half of it is in use.
+pub struct ConcurrencyLimiter {
+    limits: ConcurrencyLimits,
+    state: Mutex<State>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(limits: ConcurrencyLimits) -> Self {
+        Self {
+            limits,
+            state: Mutex::new(State {
+                limit: limits.initial.clamp(limits.min, limits.max) as f64,
+                in_flight: 0,
+                closed: false,
+                short_rtt: 0.0,
+                long_rtt: 0.0,
+            }),
+        }
+    }
+
+    /// Current number of permits.
+    pub fn limit(&self) -> usize {
+        self.state.lock().expect("limiter lock").limit as usize
+    }
+
+    /// Permits currently held.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().expect("limiter lock").in_flight
+    }
+
+    /// Fails every future `try_acquire` with `ProxyError::ServiceShutdown`.
+    pub fn close(&self) {
+        self.state.lock().expect("limiter lock").closed = true;
+    }
+
+    /// A permit if the limit allows one more batch right now.
+    pub fn try_acquire(self: &Arc<Self>) -> Result<Option<Permit>, ProxyError> {
+        let mut state = self.state.lock().expect("limiter lock");
+        if state.closed {
+            return Err(ProxyError::ServiceShutdown);
+        }
+        if state.in_flight >= state.limit as usize {
+            return Ok(None);
+        }
+
+        state.in_flight += 1;
+        Ok(Some(Permit {
+            limiter: self.clone(),
+            in_flight: state.in_flight,
+        }))
+    }
+
+    /// Whether `try_acquire` would hand out a permit right now.
+    pub fn has_room(&self) -> Result<bool, ProxyError> {
+        let state = self.state.lock().expect("limiter lock");
+        if state.closed {
+            return Err(ProxyError::ServiceShutdown);
+        }
+
+        Ok(state.in_flight < state.limit as usize)
+    }
+
+    fn succeeded(&self, latency: Duration, in_flight: usize) {
+        let rtt = latency.as_micros().max(1) as f64;
+        let mut state = self.state.lock().expect("limiter lock");
+
+        if state.long_rtt == 0.0 {
+            state.short_rtt = rtt;
+            state.long_rtt = rtt;
+        } else {
+            state.short_rtt = 0.75 * state.short_rtt + 0.25 * rtt;
+            state.long_rtt = (63.0 * state.long_rtt + rtt) / 64.0;
+        }
+        // After a slow period the long-term average would otherwise keep the limit up too long
+        if state.long_rtt > 2.0 * state.short_rtt {
+            state.long_rtt = 0.9 * state.long_rtt + 0.1 * state.short_rtt;
+        }
+
+        let gradient = (TOLERANCE * state.long_rtt / state.short_rtt).clamp(0.5, 1.0);
+        if gradient == 1.0 && (in_flight as f64) < state.limit / 2.0 {
+            // Not enough traffic to tell whether the replica could take more
+            return;
+        }
+
+        let estimate = state.limit * gradient + state.limit.sqrt();
+        let limit = (1.0 - SMOOTHING) * state.limit + SMOOTHING * estimate;
+        self.set_limit(&mut state, limit);
+    }
+
+    fn failed(&self) {
+        let mut state = self.state.lock().expect("limiter lock");
+        let limit = state.limit * BACKOFF;
+        self.set_limit(&mut state, limit);
+    }
+
+    fn set_limit(&self, state: &mut State, limit: f64) {
+        let before = state.limit as usize;
+        state.limit = limit.clamp(self.limits.min as f64, self.limits.max as f64);
+
+        let after = state.limit as usize;
+        if after != before {
+            tracing::debug!(before, after, "concurrency limit changed");
+        }
+    }
+}
+
+/// One batch admitted by a [`ConcurrencyLimiter`], released when dropped.
+pub struct Permit {
+    limiter: Arc<ConcurrencyLimiter>,
+    /// Batches in flight, this one included, when it was admitted.
+    in_flight: usize,
+}
+
+impl Permit {
+    /// Feeds the latency of a successful batch into the limit.
+    pub fn succeeded(&self, latency: Duration) {
+        self.limiter.succeeded(latency, self.in_flight);
+    }
+
+    /// Shrinks the limit after a batch the replica failed or turned away.
+    pub fn failed(&self) {
+        self.limiter.failed();
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.state.lock().expect("limiter lock").in_flight -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(initial: usize, min: usize, max: usize) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(ConcurrencyLimits { initial, min, max }))
+    }
+
+    /// Takes every permit currently available.
+    fn all_permits(limiter: &Arc<ConcurrencyLimiter>) -> Vec<Permit> {
+        std::iter::from_fn(|| limiter.try_acquire().unwrap()).collect()
+    }
+
+    #[test]
+    fn grows_while_latency_holds_and_shrinks_when_it_rises() {
+        let limiter = limiter(4, 1, 32);
+
+        for _ in 0..50 {
+            let permits = all_permits(&limiter);
+            for permit in &permits {
+                permit.succeeded(Duration::from_millis(10));
+            }
+        }
+        let grown = limiter.limit();
+        assert!(grown > 4, "limit {grown}");
+
+        // Batches now take five times as long: requests are queueing on the replica
+        for _ in 0..20 {
+            let permit = limiter.try_acquire().unwrap().unwrap();
+            permit.succeeded(Duration::from_millis(50));
+        }
+        assert!(limiter.limit() < grown, "limit {} >= {grown}", limiter.limit());
+    }
+
+    #[test]
+    fn failures_cut_the_limit_down_to_the_minimum() {
+        let limiter = limiter(8, 2, 32);
+
+        for _ in 0..50 {
+            limiter.try_acquire().unwrap().unwrap().failed();
+        }
+        assert_eq!(limiter.limit(), 2);
+
+        // Only two batches at a time now
+        let _a = limiter.try_acquire().unwrap().unwrap();
+        let b = limiter.try_acquire().unwrap().unwrap();
+        assert!(limiter.try_acquire().unwrap().is_none());
+        assert!(!limiter.has_room().unwrap());
+
+        drop(b);
+        assert!(limiter.has_room().unwrap());
+        let _c = limiter.try_acquire().unwrap().unwrap();
+
+        limiter.close();
+        assert!(matches!(limiter.try_acquire(), Err(ProxyError::ServiceShutdown)));
+    }
+}
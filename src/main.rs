@@ -1,84 +1,165 @@
 mod api;
 mod batcher;
+mod cache;
+mod circuit;
+mod config;
+mod disk_cache;
 mod error;
+mod inflight;
+mod limiter;
+mod metrics;
+mod openai;
+mod queue;
+mod retry;
+mod shutdown;
+mod telemetry;
+mod tenant;
+mod tokens;
+mod upstream;
+mod wait;
 
 use crate::batcher::{BatchSender, Batcher};
+use crate::cache::EmbeddingCache;
+use crate::circuit::CircuitBreaker;
+use crate::config::AppConfig;
+use crate::error::ProxyError;
+use crate::metrics::Metrics;
+use crate::retry::RetryPolicy;
+use crate::shutdown::Shutdown;
+use crate::tenant::Tenants;
+use crate::upstream::Upstreams;
+use actix_web::dev::Service;
 use actix_web::{App, HttpServer, web};
-use std::env;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
-
-#[derive(Clone)]
-pub struct AppConfig {
-    pub bind_addr: String,
-    pub tei_url: String,
-    pub max_wait_time: u64,
-    pub max_batch_size: usize,
-    pub batch_concurrency: usize,
-    pub queue_cap: usize,
-    pub enqueue_timeout_ms: u64,
-}
-
-impl Default for AppConfig {
-    fn default() -> Self {
-        let bind_addr = env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0:3000".into());
-        let tei_url = env::var("TEI_URL").unwrap_or_else(|_| "http://tei:80".into());
-        let max_wait_time = env::var("MAX_WAIT_TIME_MS")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(8);
-        let max_batch_size = env::var("MAX_BATCH_SIZE")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(32);
-        let batch_concurrency = env::var("BATCH_CONCURRENCY")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(4);
-        let queue_cap = env::var("QUEUE_CAP").ok().and_then(|s| s.parse().ok()).unwrap_or(2048);
-        let enqueue_timeout_ms = env::var("ENQUEUE_TIMEOUT_MS")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(75);
-
-        Self {
-            bind_addr,
-            tei_url,
-            max_wait_time,
-            max_batch_size,
-            batch_concurrency,
-            queue_cap,
-            enqueue_timeout_ms,
-        }
-    }
-}
+use tracing::{Instrument, Span};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    tracing_subscriber::fmt().with_env_filter("info").init();
+    let cfg = AppConfig::load();
+    let tracer = telemetry::init(&cfg);
 
-    let cfg = AppConfig::default();
+    let metrics = Arc::new(Metrics::default());
+    let tenants = Arc::new(Tenants::from_config(&cfg));
+    let cache = EmbeddingCache::from_config(&cfg, metrics.clone());
+    let breaker = CircuitBreaker::from_config(&cfg);
+    let shutdown = Arc::new(Shutdown::default());
     let (tx, rx) = mpsc::channel::<batcher::BatchItem>(cfg.queue_cap);
-    let upstream = Arc::new(BatchSender::new(tx));
+    let (tx_high, rx_high) = mpsc::channel::<batcher::BatchItem>(cfg.queue_cap);
+    let upstream = Arc::new(
+        BatchSender::new(tx)
+            .with_priority_lane(tx_high)
+            .with_tenants(tenants.clone())
+            .with_cache(cache.clone())
+            .with_shutdown(shutdown.clone())
+            .with_estimator(tokens::estimator(&cfg))
+            .with_enqueue_timeout(Duration::from_millis(cfg.enqueue_timeout_ms)),
+    );
 
-    Batcher::new(&cfg, rx).run(); // run batcher
+    let upstreams = Arc::new(Upstreams::from_config(&cfg));
+    if cfg.health_check_interval_ms > 0 {
+        upstreams.spawn_health_checks(
+            Duration::from_millis(cfg.health_check_interval_ms),
+            Duration::from_millis(cfg.health_check_timeout_ms),
+        );
+    }
+    let tei_urls: Vec<_> = upstreams.replicas().iter().map(|r| r.url.as_str()).collect();
+    Batcher::new(&cfg, rx, upstreams.clone(), metrics.clone())
+        .with_priority_lane(rx_high)
+        .with_tenants(tenants)
+        .with_cache(cache)
+        .with_circuit_breaker(breaker.clone())
+        .with_retries(RetryPolicy::from_config(&cfg))
+        .with_shutdown(shutdown.clone())
+        .run(); // run batcher
 
     // Server
     tracing::info!(
-        "starting proxy on {} → TEI {} (wait={}ms, max_batch={})",
+        "starting proxy on {} → TEI {} ({:?}, wait={}ms, max_batch={}, max_tokens={}, mode={:?})",
         cfg.bind_addr,
-        cfg.tei_url,
-        cfg.max_wait_time,
-        cfg.max_batch_size
+        tei_urls.join(","),
+        cfg.balance,
+        cfg.max_wait_time_ms,
+        cfg.max_batch_size,
+        cfg.max_batch_tokens,
+        cfg.batch_mode
     );
 
-    HttpServer::new(move || {
-        App::new()
+    let server_upstreams = upstreams.clone();
+    let readiness = api::Readiness::from_config(&cfg);
+    let grace = Duration::from_millis(cfg.shutdown_grace_ms);
+    let server = HttpServer::new(move || {
+        let mut app = App::new()
+            .app_data(web::Data::new(readiness))
             .app_data(web::Data::from(upstream.clone()))
-            .service(api::health)
-            .service(api::embed)
+            .app_data(web::Data::from(server_upstreams.clone()))
+            .app_data(web::Data::from(metrics.clone()));
+        if let Some(breaker) = &breaker {
+            app = app.app_data(web::Data::from(breaker.clone()));
+        }
+
+        let metrics = metrics.clone();
+        app.wrap_fn(move |req, srv| {
+            // Scrapes would otherwise dominate the request counts and traces
+            let counted = req.path() != "/metrics";
+            let span = if counted {
+                telemetry::request_span(&req)
+            } else {
+                Span::none()
+            };
+            let metrics = metrics.clone();
+            let res = span.in_scope(|| srv.call(req));
+
+            async move {
+                let res = res.await?;
+                if counted {
+                    let error = res.response().error().and_then(|e| e.as_error::<ProxyError>());
+                    metrics.record_response(res.status().as_u16(), error);
+                    Span::current().record("http.status_code", res.status().as_u16());
+                }
+                Ok(res)
+            }
+            .instrument(span)
+        })
+        .service(api::health)
+        .service(api::ready)
+        .service(api::metrics)
+        .service(api::embed)
+        .service(openai::embeddings)
     })
+    .disable_signals()
+    .shutdown_timeout(grace.as_secs().max(1))
     .bind(cfg.bind_addr)?
-    .run()
-    .await
+    .run();
+
+    // Stop accepting connections but let the open requests get their answers: the batcher
+    // flushes what it holds at once instead of waiting for batches to fill
+    let handle = server.handle();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown::signal().await;
+            tracing::info!(grace_ms = grace.as_millis() as u64, "shutting down");
+            shutdown.trigger();
+            handle.stop(true).await;
+        }
+    });
+    server.await?;
+
+    shutdown.trigger();
+    let deadline = shutdown.triggered_at().unwrap_or_else(tokio::time::Instant::now) + grace;
+    if tokio::time::timeout_at(deadline, shutdown.flushes_done())
+        .await
+        .is_err()
+    {
+        tracing::warn!("grace period over with batches still in flight");
+    }
+
+    // Flushes still waiting for a replica would outlive the server; answer them instead
+    upstreams.close();
+    if let Some(tracer) = tracer {
+        let _ = tracer.shutdown();
+    }
+    Ok(())
 }
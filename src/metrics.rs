@@ -0,0 +1,376 @@
+use crate::error::ProxyError;
+use crate::queue::Priority;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Prefix of every exported metric name.
+const PREFIX: &str = "abp";
+
+/// Label name and value of one sample, if it has one.
+pub type Label<'a> = Option<(&'a str, &'a str)>;
+
+/// Counters shared by the HTTP layer, the sender, the batcher and its flush tasks, exported in
+/// Prometheus text format on `/metrics`.
+pub struct Metrics {
+    /// Items dropped before going upstream because their caller had already gone away.
+    pub cancelled: AtomicU64,
+    /// Items rejected because their deadline passed before they went upstream.
+    pub expired: AtomicU64,
+    /// Items that joined an identical input already queued for or in flight upstream.
+    pub coalesced: AtomicU64,
+    /// Upstream calls repeated after a retryable failure.
+    pub retries: AtomicU64,
+    /// Rejected batches split in halves to isolate the inputs TEI refuses.
+    pub bisections: AtomicU64,
+    /// Times the batcher's accumulator task panicked and was started again.
+    pub batcher_restarts: AtomicU64,
+    /// Flush tasks that panicked; their waiters are answered with an error.
+    pub flush_panics: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    /// Cache hits served from disk after missing in memory; included in `cache_hits`.
+    pub cache_disk_hits: AtomicU64,
+    /// Cache entries dropped to stay within the entry or byte limit.
+    pub cache_evictions: AtomicU64,
+    /// Batch wait window in microseconds: the fixed one, or the one last chosen by the adaptive wait.
+    pub wait_time_us: AtomicU64,
+    /// Items the batcher pulled from the channels and holds in its queue, by priority lane.
+    held: [AtomicU64; 2],
+    /// Requests answered, by HTTP status.
+    responses: Mutex<BTreeMap<u16, u64>>,
+    /// Failed requests, by [`ProxyError::kind`].
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    /// Items per batch formed by the batcher, before coalescing.
+    pub batch_size: Histogram,
+    pub batch_tokens: Histogram,
+    /// Time from an item's enqueue until its batch was flushed, in microseconds.
+    pub queue_wait: Histogram,
+    /// Duration of every upstream call, failed ones included, in microseconds.
+    pub upstream_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            cancelled: AtomicU64::default(),
+            expired: AtomicU64::default(),
+            coalesced: AtomicU64::default(),
+            retries: AtomicU64::default(),
+            bisections: AtomicU64::default(),
+            batcher_restarts: AtomicU64::default(),
+            flush_panics: AtomicU64::default(),
+            cache_hits: AtomicU64::default(),
+            cache_misses: AtomicU64::default(),
+            cache_disk_hits: AtomicU64::default(),
+            cache_evictions: AtomicU64::default(),
+            wait_time_us: AtomicU64::default(),
+            held: Default::default(),
+            responses: Mutex::default(),
+            errors: Mutex::default(),
+            batch_size: Histogram::new(&[1, 2, 4, 8, 16, 32, 64, 128, 256], 1.0),
+            batch_tokens: Histogram::new(&[64, 256, 1024, 2048, 4096, 8192, 16384, 32768, 65536], 1.0),
+            queue_wait: Histogram::new(
+                &[
+                    500, 1_000, 2_000, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000,
+                ],
+                1e6,
+            ),
+            upstream_latency: Histogram::new(
+                &[
+                    5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000, 5_000_000,
+                    10_000_000,
+                ],
+                1e6,
+            ),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn add_cancelled(&self, n: usize) {
+        add(&self.cancelled, n);
+    }
+
+    pub fn add_expired(&self, n: usize) {
+        add(&self.expired, n);
+    }
+
+    pub fn add_coalesced(&self, n: usize) {
+        add(&self.coalesced, n);
+    }
+
+    pub fn add_retries(&self, n: usize) {
+        add(&self.retries, n);
+    }
+
+    pub fn add_bisections(&self, n: usize) {
+        add(&self.bisections, n);
+    }
+
+    pub fn add_batcher_restarts(&self, n: usize) {
+        add(&self.batcher_restarts, n);
+    }
+
+    pub fn add_flush_panics(&self, n: usize) {
+        add(&self.flush_panics, n);
+    }
+
+    pub fn add_cache_hits(&self, n: usize) {
+        add(&self.cache_hits, n);
+    }
+
+    pub fn add_cache_misses(&self, n: usize) {
+        add(&self.cache_misses, n);
+    }
+
+    pub fn add_cache_disk_hits(&self, n: usize) {
+        add(&self.cache_disk_hits, n);
+    }
+
+    pub fn add_cache_evictions(&self, n: usize) {
+        add(&self.cache_evictions, n);
+    }
+
+    pub fn set_wait_time(&self, wait: Duration) {
+        self.wait_time_us.store(wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_held(&self, priority: Priority, n: usize) {
+        self.held[priority as usize].store(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn held(&self, priority: Priority) -> usize {
+        load(&self.held[priority as usize]) as usize
+    }
+
+    /// Counts a request answered with `status`, and its error if it failed with a [`ProxyError`].
+    pub fn record_response(&self, status: u16, error: Option<&ProxyError>) {
+        *self.responses.lock().expect("metrics lock").entry(status).or_default() += 1;
+        if let Some(e) = error {
+            *self.errors.lock().expect("metrics lock").entry(e.kind()).or_default() += 1;
+        }
+    }
+
+    /// Appends every metric kept here to `out`, in Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        let responses: Vec<_> = self
+            .responses
+            .lock()
+            .expect("metrics lock")
+            .clone()
+            .into_iter()
+            .collect();
+        let statuses: Vec<_> = responses.iter().map(|(status, _)| status.to_string()).collect();
+        write_family(
+            out,
+            "requests_total",
+            "counter",
+            "Requests answered, by HTTP status.",
+            responses
+                .iter()
+                .zip(&statuses)
+                .map(|((_, n), status)| (Some(("status", status.as_str())), *n as f64)),
+        );
+
+        let errors = self.errors.lock().expect("metrics lock").clone();
+        write_family(
+            out,
+            "errors_total",
+            "counter",
+            "Failed requests, by error kind.",
+            errors.iter().map(|(kind, n)| (Some(("kind", *kind)), *n as f64)),
+        );
+
+        for (name, help, counter) in [
+            (
+                "items_cancelled_total",
+                "Items dropped because their caller went away.",
+                &self.cancelled,
+            ),
+            (
+                "items_expired_total",
+                "Items rejected because their deadline passed.",
+                &self.expired,
+            ),
+            (
+                "items_coalesced_total",
+                "Items that joined an identical input in flight.",
+                &self.coalesced,
+            ),
+            (
+                "upstream_retries_total",
+                "Upstream calls repeated after a retryable failure.",
+                &self.retries,
+            ),
+            (
+                "batch_bisections_total",
+                "Rejected batches split in halves.",
+                &self.bisections,
+            ),
+            (
+                "batcher_restarts_total",
+                "Times the batcher panicked and was restarted.",
+                &self.batcher_restarts,
+            ),
+            ("flush_panics_total", "Flush tasks that panicked.", &self.flush_panics),
+            ("cache_hits_total", "Inputs answered from the cache.", &self.cache_hits),
+            (
+                "cache_misses_total",
+                "Inputs not found in the cache.",
+                &self.cache_misses,
+            ),
+            (
+                "cache_disk_hits_total",
+                "Cache hits served from disk.",
+                &self.cache_disk_hits,
+            ),
+            (
+                "cache_evictions_total",
+                "Cache entries evicted to stay within limits.",
+                &self.cache_evictions,
+            ),
+        ] {
+            write_family(out, name, "counter", help, [(None, load(counter) as f64)]);
+        }
+
+        write_family(
+            out,
+            "batch_wait_seconds",
+            "gauge",
+            "Batch wait window, fixed or last chosen by the adaptive wait.",
+            [(None, load(&self.wait_time_us) as f64 / 1e6)],
+        );
+
+        self.batch_size.render(out, "batch_size", "Items per batch.");
+        self.batch_tokens
+            .render(out, "batch_tokens", "Estimated tokens per batch.");
+        self.queue_wait.render(
+            out,
+            "queue_wait_seconds",
+            "Time from enqueue until the item's batch was flushed.",
+        );
+        self.upstream_latency
+            .render(out, "upstream_latency_seconds", "Duration of upstream calls.");
+    }
+}
+
+/// Cumulative histogram of `u64` observations over fixed upper `bounds`. Values are rendered
+/// divided by `scale`, e.g. microseconds as seconds with `scale = 1e6`.
+pub struct Histogram {
+    bounds: &'static [u64],
+    scale: f64,
+    /// Observations per bound, not cumulative; the last one counts those above every bound.
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64], scale: f64) -> Self {
+        Self {
+            bounds,
+            scale,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::default()).collect(),
+            sum: AtomicU64::default(),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let idx = self.bounds.partition_point(|&bound| bound < value);
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_micros() as u64);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+        let _ = writeln!(out, "# TYPE {PREFIX}_{name} histogram");
+
+        let mut count = 0;
+        for (idx, bucket) in self.buckets.iter().enumerate() {
+            count += load(bucket);
+            let le = match self.bounds.get(idx) {
+                Some(&bound) => (bound as f64 / self.scale).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{PREFIX}_{name}_bucket{{le=\"{le}\"}} {count}");
+        }
+        let _ = writeln!(out, "{PREFIX}_{name}_sum {}", load(&self.sum) as f64 / self.scale);
+        let _ = writeln!(out, "{PREFIX}_{name}_count {count}");
+    }
+}
+
+/// Appends a metric family of `kind` (`counter`, `gauge`) to `out`, one sample per `(label, value)`.
+pub fn write_family<'a>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (Label<'a>, f64)>,
+) {
+    let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name} {kind}");
+
+    for (label, value) in samples {
+        match label {
+            Some((key, val)) => {
+                let val = val.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+                let _ = writeln!(out, "{PREFIX}_{name}{{{key}=\"{val}\"}} {value}");
+            }
+            None => {
+                let _ = writeln!(out, "{PREFIX}_{name} {value}");
+            }
+        }
+    }
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+fn add(counter: &AtomicU64, n: usize) {
+    if n > 0 {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_renders_cumulative_buckets_in_scaled_units() {
+        let hist = Histogram::new(&[1_000, 10_000], 1e6);
+        for us in [500, 2_000, 3_000, 50_000] {
+            hist.observe(us);
+        }
+
+        let mut out = String::new();
+        hist.render(&mut out, "wait_seconds", "Wait.");
+        assert!(out.contains("abp_wait_seconds_bucket{le=\"0.001\"} 1\n"), "{out}");
+        assert!(out.contains("abp_wait_seconds_bucket{le=\"0.01\"} 3\n"), "{out}");
+        assert!(out.contains("abp_wait_seconds_bucket{le=\"+Inf\"} 4\n"), "{out}");
+        assert!(out.contains("abp_wait_seconds_sum 0.0555\n"), "{out}");
+        assert!(out.contains("abp_wait_seconds_count 4\n"), "{out}");
+    }
+
+    #[test]
+    fn responses_are_counted_by_status_and_error_kind() {
+        let metrics = Metrics::default();
+        metrics.record_response(200, None);
+        metrics.record_response(200, None);
+        metrics.record_response(429, Some(&ProxyError::QueueFull { retry_after: 1 }));
+
+        let mut out = String::new();
+        metrics.render(&mut out);
+        assert!(out.contains("abp_requests_total{status=\"200\"} 2\n"), "{out}");
+        assert!(out.contains("abp_requests_total{status=\"429\"} 1\n"), "{out}");
+        assert!(out.contains("abp_errors_total{kind=\"queue_full\"} 1\n"), "{out}");
+        assert!(out.contains("abp_items_cancelled_total 0\n"), "{out}");
+    }
+}
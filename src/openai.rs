@@ -0,0 +1,204 @@
+use crate::api::{BodyOptions, EmbedInput, request_options};
+use crate::batcher::BatchSender;
+use crate::error::ProxyError;
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum EncodingFormat {
+    #[default]
+    Float,
+    Base64,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsReq {
+    model: String,
+    input: EmbedInput,
+    #[serde(default)]
+    encoding_format: EncodingFormat,
+    dimensions: Option<usize>,
+    #[serde(flatten)]
+    opts: BodyOptions,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum EmbeddingValue {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Serialize)]
+struct EmbeddingData {
+    object: &'static str,
+    index: usize,
+    embedding: EmbeddingValue,
+}
+
+#[derive(Serialize)]
+struct Usage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct EmbeddingsResp {
+    object: &'static str,
+    data: Vec<EmbeddingData>,
+    model: String,
+    usage: Usage,
+}
+
+/// OpenAI-compatible embeddings endpoint, backed by the same batcher as `/embed`.
+#[post("/v1/embeddings")]
+async fn embeddings(
+    req: HttpRequest,
+    upstream: web::Data<BatchSender>,
+    body: web::Json<EmbeddingsReq>,
+) -> Result<impl Responder, ProxyError> {
+    let EmbeddingsReq {
+        model,
+        input,
+        encoding_format,
+        dimensions,
+        opts,
+    } = body.into_inner();
+
+    let inputs = match input {
+        EmbedInput::Single(s) => vec![s],
+        EmbedInput::Batch(v) => v,
+    };
+    if inputs.is_empty() {
+        return Err(ProxyError::InvalidRequest("input must not be empty".into()));
+    }
+    if dimensions == Some(0) {
+        return Err(ProxyError::InvalidRequest("dimensions must be greater than 0".into()));
+    }
+
+    let opts = request_options(&req, &opts)?;
+    let prompt_tokens = inputs.iter().map(|s| upstream.estimate_tokens(s)).sum();
+    let embeddings = upstream.request_many(inputs, &opts).await?;
+
+    let mut data = Vec::with_capacity(embeddings.len());
+    for (index, mut embedding) in embeddings.into_iter().enumerate() {
+        if let Some(dims) = dimensions {
+            shorten(&mut embedding, dims)?;
+        }
+
+        let embedding = match encoding_format {
+            EncodingFormat::Float => EmbeddingValue::Float(embedding),
+            EncodingFormat::Base64 => EmbeddingValue::Base64(encode_base64(&embedding)),
+        };
+        data.push(EmbeddingData {
+            object: "embedding",
+            index,
+            embedding,
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(EmbeddingsResp {
+        object: "list",
+        data,
+        model,
+        usage: Usage {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        },
+    }))
+}
+
+/// Truncates to the first `dims` components and re-normalizes to unit length,
+/// which is how Matryoshka-trained models expect shortened embeddings to be used.
+fn shorten(embedding: &mut Vec<f32>, dims: usize) -> Result<(), ProxyError> {
+    if dims > embedding.len() {
+        return Err(ProxyError::InvalidRequest(format!(
+            "dimensions {dims} exceeds model dimensions {}",
+            embedding.len()
+        )));
+    }
+
+    embedding.truncate(dims);
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+
+    Ok(())
+}
+
+/// Little-endian f32 bytes, base64 encoded, as returned by OpenAI for `encoding_format: "base64"`.
+fn encode_base64(embedding: &[f32]) -> String {
+    let bytes: Vec<u8> = embedding.iter().flat_map(|v| v.to_le_bytes()).collect();
+    STANDARD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, test};
+
+    #[actix_web::test]
+    async fn embeddings_returns_openai_envelope() {
+        let sender = BatchSender::answering(vec![1.0, 2.0, 3.5]);
+        let app = test::init_service(App::new().app_data(web::Data::new(sender)).service(embeddings)).await;
+        let req = test::TestRequest::post()
+            .uri("/v1/embeddings")
+            .set_json(serde_json::json!({ "model": "nomic", "input": ["hello", "world!!!!"] }))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["object"], "list");
+        assert_eq!(body["model"], "nomic");
+        assert_eq!(body["data"][1]["object"], "embedding");
+        assert_eq!(body["data"][1]["index"], 1);
+        assert_eq!(body["data"][0]["embedding"], serde_json::json!([1.0, 2.0, 3.5]));
+        assert_eq!(body["usage"]["prompt_tokens"], 5);
+        assert_eq!(body["usage"]["total_tokens"], 5);
+    }
+
+    #[actix_web::test]
+    async fn embeddings_base64_with_dimensions() {
+        let sender = BatchSender::answering(vec![3.0, 4.0, 12.0]);
+        let app = test::init_service(App::new().app_data(web::Data::new(sender)).service(embeddings)).await;
+        let req = test::TestRequest::post()
+            .uri("/v1/embeddings")
+            .set_json(serde_json::json!({
+                "model": "nomic",
+                "input": "hello",
+                "encoding_format": "base64",
+                "dimensions": 2,
+            }))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let bytes = STANDARD.decode(body["data"][0]["embedding"].as_str().unwrap()).unwrap();
+        let floats: Vec<f32> = bytes
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(floats, vec![0.6, 0.8]);
+    }
+
+    #[actix_web::test]
+    async fn embeddings_rejects_oversized_dimensions() {
+        let sender = BatchSender::answering(vec![1.0, 2.0]);
+        let app = test::init_service(App::new().app_data(web::Data::new(sender)).service(embeddings)).await;
+        let req = test::TestRequest::post()
+            .uri("/v1/embeddings")
+            .set_json(serde_json::json!({ "model": "nomic", "input": "hello", "dimensions": 8 }))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,581 @@
+use crate::batcher::BatchItem;
+use crate::config::AppConfig;
+use crate::tenant::{TenantId, Tenants};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How the batcher groups queued items into batches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BatchMode {
+    /// Arrival order, one queue.
+    Fifo,
+    /// One queue per length bucket, so short and long inputs are not padded together.
+    Bucketed,
+}
+
+impl FromStr for BatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fifo" => Ok(BatchMode::Fifo),
+            "bucketed" => Ok(BatchMode::Bucketed),
+            other => Err(format!("unknown batch mode `{other}` (expected `fifo` or `bucketed`)")),
+        }
+    }
+}
+
+/// Scheduling class of a request. Interactive traffic goes `High`; everything else,
+/// including requests that do not say, goes `Low`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    High,
+    #[default]
+    Low,
+}
+
+impl Priority {
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "high" => Ok(Priority::High),
+            "low" => Ok(Priority::Low),
+            other => Err(format!("unknown priority `{other}` (expected `high` or `low`)")),
+        }
+    }
+}
+
+const HIGH: usize = Priority::High as usize;
+const LOW: usize = Priority::Low as usize;
+
+/// Limits every bucket is flushed on.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueLimits {
+    pub max_batch_size: usize,
+    pub max_batch_tokens: usize,
+    /// Longest a low-priority item waits for its bucket to fill.
+    pub max_wait: Duration,
+    /// Longest a high-priority item waits for its bucket to fill.
+    pub high_priority_max_wait: Duration,
+    /// Slots of every batch kept for waiting low-priority items, so they are never starved
+    /// by a steady stream of high-priority ones. Capped at half the batch.
+    pub low_priority_min_slots: usize,
+    /// Time reserved for the upstream call: a bucket is flushed this long before its tightest item deadline.
+    pub deadline_margin: Duration,
+    /// Items the batcher holds while it waits for upstream capacity, see [`BatchQueue::capacity`].
+    pub max_held: usize,
+}
+
+impl QueueLimits {
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        Self {
+            max_batch_size: cfg.max_batch_size,
+            max_batch_tokens: cfg.max_batch_tokens,
+            max_wait: Duration::from_millis(cfg.max_wait_time_ms),
+            high_priority_max_wait: Duration::from_millis(cfg.high_priority_max_wait_ms),
+            low_priority_min_slots: cfg.low_priority_min_slots,
+            deadline_margin: Duration::from_millis(cfg.deadline_margin_ms),
+            max_held: cfg.queue_cap,
+        }
+    }
+}
+
+struct Pending {
+    item: BatchItem,
+    /// When the batcher picked the item up; the bucket deadline counts from the oldest one.
+    arrived: Instant,
+}
+
+#[derive(Default)]
+struct TenantQueue {
+    items: VecDeque<Pending>,
+    /// Tokens this tenant may still send before yielding its turn.
+    deficit: usize,
+}
+
+/// Items of one priority, scheduled across tenants by deficit round robin: the tenant
+/// at the front of `ring` is served while its deficit covers the next item's tokens,
+/// then it is granted `quantum * weight` more tokens and moves to the back. The quantum
+/// is the cheapest item at the front of any tenant's queue, so a tenant of weight 1 gets
+/// about one item per turn whatever the batch limits.
+#[derive(Default)]
+struct Lane {
+    tenants: HashMap<TenantId, TenantQueue>,
+    /// Tenants with queued items, in turn order.
+    ring: VecDeque<TenantId>,
+    len: usize,
+}
+
+impl Lane {
+    fn push(&mut self, p: Pending) {
+        let tenant = p.item.tenant.clone();
+        let queue = self.tenants.entry(tenant.clone()).or_default();
+        if queue.items.is_empty() {
+            self.ring.push_back(tenant);
+        }
+
+        queue.items.push_back(p);
+        self.len += 1;
+    }
+
+    fn oldest(&self) -> Option<Instant> {
+        self.tenants
+            .values()
+            .filter_map(|q| q.items.front())
+            .map(|p| p.arrived)
+            .min()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Pending> {
+        self.tenants.values().flat_map(|q| q.items.iter())
+    }
+
+    /// Tokens granted per turn and unit of weight.
+    fn quantum(&self) -> usize {
+        let cheapest = self.tenants.values().filter_map(|q| q.items.front());
+        cheapest.map(|p| p.item.tokens).min().unwrap_or(1).max(1)
+    }
+
+    /// Token cost of the item `pop` would return next, rotating turns as needed.
+    fn peek(&mut self, tenants: &Tenants) -> Option<usize> {
+        let quantum = self.quantum();
+        loop {
+            let tenant = self.ring.front()?;
+            let queue = self.tenants.get_mut(tenant).expect("ring entries have a queue");
+            let cost = queue.items.front().expect("ring entries are non-empty").item.tokens;
+
+            if queue.deficit >= cost {
+                return Some(cost);
+            }
+
+            queue.deficit += quantum * tenants.weight(tenant) as usize;
+            self.ring.rotate_left(1);
+        }
+    }
+
+    /// Pops the item last returned by `peek`.
+    fn pop(&mut self) -> Pending {
+        let tenant = self.ring.front().expect("peek found an item").clone();
+        let queue = self.tenants.get_mut(&tenant).expect("ring entries have a queue");
+        let p = queue.items.pop_front().expect("ring entries are non-empty");
+        queue.deficit = queue.deficit.saturating_sub(p.item.tokens);
+
+        if queue.items.is_empty() {
+            // An idle tenant does not bank credit for later
+            self.tenants.remove(&tenant);
+            self.ring.pop_front();
+        }
+
+        self.len -= 1;
+        p
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    /// One lane per priority, indexed by `Priority::lane`.
+    lanes: [Lane; 2],
+    tokens: usize,
+}
+
+impl Bucket {
+    fn len(&self) -> usize {
+        self.lanes.iter().map(|l| l.len).sum()
+    }
+
+    fn oldest(&self) -> Option<Instant> {
+        self.lanes.iter().filter_map(Lane::oldest).min()
+    }
+}
+
+/// Items held by the batcher, split into buckets by estimated token length.
+///
+/// Every bucket is flushed on its own: when it reaches `max_batch_size` items, when it
+/// reaches `max_batch_tokens`, or `max_wait` after its oldest item arrived. The last rule
+/// bounds the extra latency of any request to `max_wait`, whichever bucket it lands in.
+/// A bucket is also flushed `deadline_margin` before the tightest deadline of its items.
+///
+/// Inside a bucket, high-priority items wait at most `high_priority_max_wait` and fill
+/// batches first; low-priority ones keep `low_priority_min_slots` of every batch. Within
+/// each priority, tenants share batches in proportion to their weights.
+pub struct BatchQueue {
+    /// Inclusive upper token bound of every bucket but the last, which takes the rest.
+    boundaries: Vec<usize>,
+    buckets: Vec<Bucket>,
+    len: usize,
+    limits: QueueLimits,
+    tenants: Arc<Tenants>,
+}
+
+impl BatchQueue {
+    /// With no `boundaries` there is a single bucket, i.e. plain FIFO batching.
+    pub fn new(boundaries: &[usize], limits: QueueLimits) -> Self {
+        let mut boundaries = boundaries.to_vec();
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        Self {
+            buckets: (0..=boundaries.len()).map(|_| Bucket::default()).collect(),
+            boundaries,
+            len: 0,
+            limits,
+            tenants: Arc::new(Tenants::default()),
+        }
+    }
+
+    /// Uses the configured tenant weights instead of equal shares.
+    pub fn with_tenants(mut self, tenants: Arc<Tenants>) -> Self {
+        self.tenants = tenants;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn max_batch_size(&self) -> usize {
+        self.limits.max_batch_size
+    }
+
+    /// Changes how long low-priority items wait for their bucket to fill, including the ones already queued.
+    pub fn set_max_wait(&mut self, max_wait: Duration) {
+        self.limits.max_wait = max_wait;
+    }
+
+    /// Items held in `priority`'s lanes, across buckets.
+    pub fn len_of(&self, priority: Priority) -> usize {
+        self.buckets.iter().map(|b| b.lanes[priority.lane()].len).sum()
+    }
+
+    /// How many items the batcher should hold before it stops pulling from the channel:
+    /// `max_held`, so a backlog waits here where priorities and tenant shares apply, but at
+    /// least one full batch per bucket, so at capacity some bucket is always full.
+    pub fn capacity(&self) -> usize {
+        self.limits
+            .max_held
+            .max(self.limits.max_batch_size * self.buckets.len())
+    }
+
+    pub fn push(&mut self, item: BatchItem, now: Instant) {
+        let idx = self.boundaries.partition_point(|&bound| bound < item.tokens);
+        let bucket = &mut self.buckets[idx];
+
+        bucket.tokens += item.tokens;
+        bucket.lanes[item.priority.lane()].push(Pending { item, arrived: now });
+        self.len += 1;
+    }
+
+    /// Earliest time at which some bucket has to be flushed.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.buckets.iter().filter_map(|b| self.flush_at(b)).min()
+    }
+
+    /// Pops a batch from a bucket that is full or past its deadline. When several are,
+    /// the one with the earliest deadline goes first.
+    pub fn pop_ready(&mut self, now: Instant) -> Option<Vec<BatchItem>> {
+        let idx = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, b)| self.flush_at(b).map(|at| (idx, b, at)))
+            .filter(|(_, b, at)| {
+                b.len() >= self.limits.max_batch_size || b.tokens >= self.limits.max_batch_tokens || now >= *at
+            })
+            .min_by_key(|(_, _, at)| *at)
+            .map(|(idx, _, _)| idx)?;
+
+        Some(self.take(idx))
+    }
+
+    /// Pops a batch from the bucket holding the oldest item, ignoring limits and deadlines.
+    pub fn pop_oldest(&mut self) -> Option<Vec<BatchItem>> {
+        let idx = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, b)| b.oldest().map(|arrived| (idx, arrived)))
+            .min_by_key(|(_, arrived)| *arrived)
+            .map(|(idx, _)| idx)?;
+
+        Some(self.take(idx))
+    }
+
+    /// Removes and returns every item, leaving the queue empty.
+    pub fn drain(&mut self) -> Vec<BatchItem> {
+        let empty = (0..=self.boundaries.len()).map(|_| Bucket::default()).collect();
+        self.len = 0;
+
+        std::mem::replace(&mut self.buckets, empty)
+            .into_iter()
+            .flat_map(|b| b.lanes)
+            .flat_map(|l| l.tenants.into_values())
+            .flat_map(|q| q.items)
+            .map(|p| p.item)
+            .collect()
+    }
+
+    /// When `bucket` must be flushed: the max wait of its oldest item in either lane, or
+    /// `deadline_margin` before the tightest item deadline, whichever comes first.
+    fn flush_at(&self, bucket: &Bucket) -> Option<Instant> {
+        let high = bucket.lanes[HIGH]
+            .oldest()
+            .map(|arrived| arrived + self.limits.high_priority_max_wait);
+        let low = bucket.lanes[LOW].oldest().map(|arrived| arrived + self.limits.max_wait);
+        let tightest = bucket
+            .lanes
+            .iter()
+            .flat_map(Lane::iter)
+            .filter_map(|p| p.item.deadline)
+            .min()
+            .map(|d| d.checked_sub(self.limits.deadline_margin).unwrap_or(d));
+
+        [high, low, tightest].into_iter().flatten().min()
+    }
+
+    /// Takes items off the front of a bucket while they fit the item and token limits.
+    /// The first item is always taken, even if it alone exceeds the token budget.
+    ///
+    /// High-priority items go first, up to the slots reserved for waiting low-priority
+    /// items; slots the low lane cannot use go back to high-priority items.
+    fn take(&mut self, idx: usize) -> Vec<BatchItem> {
+        let limits = self.limits;
+        let bucket = &mut self.buckets[idx];
+        let reserved = if bucket.lanes[LOW].len == 0 {
+            0
+        } else {
+            limits.low_priority_min_slots.min(limits.max_batch_size / 2)
+        };
+        let mut batch = Vec::with_capacity(limits.max_batch_size.min(bucket.len()));
+        let mut tokens = 0;
+
+        for (lane, max_items) in [
+            (HIGH, limits.max_batch_size - reserved),
+            (LOW, limits.max_batch_size),
+            (HIGH, limits.max_batch_size),
+        ] {
+            let lane = &mut bucket.lanes[lane];
+
+            while batch.len() < max_items {
+                let Some(cost) = lane.peek(&self.tenants) else {
+                    break;
+                };
+                if !batch.is_empty() && tokens + cost > limits.max_batch_tokens {
+                    break;
+                }
+
+                let p = lane.pop();
+                tokens += p.item.tokens;
+                batch.push(p.item);
+            }
+        }
+
+        bucket.tokens -= tokens;
+        self.len -= batch.len();
+        batch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(input: &str, tokens: usize) -> BatchItem {
+        BatchItem::test(input, tokens).0
+    }
+
+    fn of(tenant: &str, input: &str) -> BatchItem {
+        let mut item = item(input, 10);
+        item.tenant = Some(tenant.into());
+        item
+    }
+
+    fn high(input: &str) -> BatchItem {
+        let mut item = item(input, 1);
+        item.priority = Priority::High;
+        item
+    }
+
+    fn limits(max_batch_size: usize, max_wait_ms: u64) -> QueueLimits {
+        QueueLimits {
+            max_batch_size,
+            max_batch_tokens: 10_000,
+            max_wait: Duration::from_millis(max_wait_ms),
+            high_priority_max_wait: Duration::from_millis(max_wait_ms),
+            low_priority_min_slots: 0,
+            deadline_margin: Duration::from_millis(5),
+            max_held: 0,
+        }
+    }
+
+    fn inputs(batch: Vec<BatchItem>) -> Vec<String> {
+        batch.into_iter().map(|i| i.input).collect()
+    }
+
+    #[test]
+    fn bucketed_queue_separates_short_and_long_inputs() {
+        let mut q = BatchQueue::new(&[16, 128], limits(2, 50));
+        let now = Instant::now();
+
+        q.push(item("short-1", 5), now);
+        q.push(item("long-1", 500), now);
+        q.push(item("mid-1", 60), now);
+        q.push(item("long-2", 400), now);
+        q.push(item("short-2", 8), now);
+
+        // Both full buckets hold same-aged items; the mid bucket waits for its deadline
+        let mut first_two = vec![inputs(q.pop_ready(now).unwrap()), inputs(q.pop_ready(now).unwrap())];
+        first_two.sort();
+        assert_eq!(first_two, vec![vec!["long-1", "long-2"], vec!["short-1", "short-2"]]);
+        assert!(q.pop_ready(now).is_none());
+        assert_eq!(q.len(), 1);
+    }
+
+    #[test]
+    fn each_bucket_flushes_on_its_own_deadline() {
+        let mut q = BatchQueue::new(&[16], limits(8, 50));
+        let t0 = Instant::now();
+
+        q.push(item("long", 100), t0);
+        q.push(item("short", 4), t0 + Duration::from_millis(20));
+        assert_eq!(q.next_deadline(), Some(t0 + Duration::from_millis(50)));
+
+        assert!(q.pop_ready(t0 + Duration::from_millis(49)).is_none());
+        assert_eq!(
+            inputs(q.pop_ready(t0 + Duration::from_millis(50)).unwrap()),
+            vec!["long"]
+        );
+        assert_eq!(q.next_deadline(), Some(t0 + Duration::from_millis(70)));
+        assert_eq!(
+            inputs(q.pop_ready(t0 + Duration::from_millis(70)).unwrap()),
+            vec!["short"]
+        );
+        assert!(q.pop_oldest().is_none());
+    }
+
+    #[test]
+    fn tight_item_deadline_pulls_bucket_deadline_forward() {
+        let mut q = BatchQueue::new(&[], limits(8, 50));
+        let t0 = Instant::now();
+
+        q.push(item("relaxed", 1), t0);
+        let mut tight = item("tight", 1);
+        tight.deadline = Some(t0 + Duration::from_millis(20));
+        q.push(tight, t0);
+
+        // Flushed deadline_margin ahead of the tightest item deadline, not after max_wait
+        assert_eq!(q.next_deadline(), Some(t0 + Duration::from_millis(15)));
+        assert!(q.pop_ready(t0 + Duration::from_millis(14)).is_none());
+        assert_eq!(
+            inputs(q.pop_ready(t0 + Duration::from_millis(15)).unwrap()),
+            vec!["relaxed", "tight"]
+        );
+    }
+
+    #[test]
+    fn high_priority_fills_first_and_waits_less() {
+        let mut lim = limits(3, 50);
+        lim.high_priority_max_wait = Duration::from_millis(5);
+        let mut q = BatchQueue::new(&[], lim);
+        let t0 = Instant::now();
+
+        q.push(item("low-1", 1), t0);
+        q.push(item("low-2", 1), t0);
+        assert_eq!(q.next_deadline(), Some(t0 + Duration::from_millis(50)));
+
+        q.push(high("high-1"), t0 + Duration::from_millis(10));
+        q.push(high("high-2"), t0 + Duration::from_millis(10));
+        assert_eq!(q.next_deadline(), Some(t0 + Duration::from_millis(15)));
+        assert_eq!(
+            inputs(q.pop_ready(t0 + Duration::from_millis(10)).unwrap()),
+            vec!["high-1", "high-2", "low-1"]
+        );
+    }
+
+    #[test]
+    fn low_priority_keeps_reserved_slots_under_high_priority_load() {
+        let mut lim = limits(4, 50);
+        lim.low_priority_min_slots = 1;
+        let mut q = BatchQueue::new(&[], lim);
+        let now = Instant::now();
+
+        q.push(item("low-1", 1), now);
+        for i in 0..8 {
+            q.push(high(&format!("high-{i}")), now);
+        }
+
+        assert_eq!(
+            inputs(q.pop_ready(now).unwrap()),
+            vec!["high-0", "high-1", "high-2", "low-1"]
+        );
+        // Without waiting low-priority items the whole batch goes to high priority
+        assert_eq!(
+            inputs(q.pop_ready(now).unwrap()),
+            vec!["high-3", "high-4", "high-5", "high-6"]
+        );
+    }
+
+    #[test]
+    fn tenants_share_batches_by_weight() {
+        let now = Instant::now();
+        let fill = |q: &mut BatchQueue| {
+            for i in 0..6 {
+                q.push(of("noisy", &format!("noisy-{i}")), now);
+            }
+            q.push(of("quiet", "quiet-0"), now);
+            q.push(of("quiet", "quiet-1"), now);
+        };
+
+        let lim = limits(4, 50);
+
+        // Equal weights: turns alternate, the late tenant is not stuck behind the backlog
+        let mut q = BatchQueue::new(&[], lim);
+        fill(&mut q);
+        assert_eq!(
+            inputs(q.pop_ready(now).unwrap()),
+            vec!["noisy-0", "quiet-0", "noisy-1", "quiet-1"]
+        );
+
+        let tenants = Tenants::from_config(&AppConfig {
+            tenant_weights: HashMap::from([("noisy".to_string(), 3)]),
+            ..AppConfig::default()
+        });
+        let mut q = BatchQueue::new(&[], lim).with_tenants(Arc::new(tenants));
+        fill(&mut q);
+        assert_eq!(
+            inputs(q.pop_ready(now).unwrap()),
+            vec!["noisy-0", "noisy-1", "noisy-2", "quiet-0"]
+        );
+    }
+
+    #[test]
+    fn quiet_tenant_makes_the_next_batch_under_default_limits() {
+        let mut q = BatchQueue::new(&[], QueueLimits::from_config(&AppConfig::default()));
+        let now = Instant::now();
+        let small = |tenant: &str, input: String| {
+            let mut item = item(&input, 5);
+            item.tenant = Some(tenant.into());
+            item
+        };
+
+        for i in 0..200 {
+            q.push(small("noisy", format!("noisy-{i}")), now);
+        }
+        q.push(small("quiet", "quiet-0".into()), now);
+
+        let batch = inputs(q.pop_oldest().unwrap());
+        assert_eq!(&batch[..3], ["noisy-0", "quiet-0", "noisy-1"]);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::config::AppConfig;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How failed batches are retried, see [`ProxyError::is_retryable`](crate::error::ProxyError::is_retryable).
+///
+/// Delays grow exponentially from `base_delay` up to `max_delay`, with full jitter. Retries draw
+/// from a budget shared by all flushes: every batch sent adds `budget_ratio` to it, every retry
+/// takes one, and it holds at most `budget_reserve`. While TEI is down, retries are thus limited
+/// to a fraction of the traffic instead of multiplying it.
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    budget_ratio: f64,
+    budget_reserve: f64,
+    budget: Mutex<f64>,
+}
+
+impl RetryPolicy {
+    pub fn new(
+        max_retries: u32,
+        base_delay: Duration,
+        max_delay: Duration,
+        budget_ratio: f64,
+        budget_reserve: f64,
+    ) -> Self {
+        Self {
+            max_retries,
+            base_delay,
+            max_delay,
+            budget_ratio,
+            budget_reserve,
+            budget: Mutex::new(budget_reserve),
+        }
+    }
+
+    /// Builds the policy described by `cfg`, or `None` when retries are disabled.
+    pub fn from_config(cfg: &AppConfig) -> Option<Arc<Self>> {
+        if cfg.max_retries == 0 {
+            return None;
+        }
+
+        Some(Arc::new(Self::new(
+            cfg.max_retries,
+            Duration::from_millis(cfg.retry_base_delay_ms),
+            Duration::from_millis(cfg.retry_max_delay_ms),
+            cfg.retry_budget_ratio,
+            cfg.retry_budget_reserve,
+        )))
+    }
+
+    /// Delay before retry number `retry` (starting at 0).
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let cap = self.base_delay.saturating_mul(1 << retry.min(16)).min(self.max_delay);
+
+        cap.mul_f64(fastrand::f64())
+    }
+
+    /// Credits the budget for a batch about to be sent for the first time.
+    pub fn deposit(&self) {
+        let mut budget = self.budget.lock().expect("retry budget lock");
+        *budget = (*budget + self.budget_ratio).min(self.budget_reserve);
+    }
+
+    /// Takes one retry from the budget, if there is one left.
+    pub fn withdraw(&self) -> bool {
+        let mut budget = self.budget.lock().expect("retry budget lock");
+        if *budget < 1.0 {
+            return false;
+        }
+
+        *budget -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(10), Duration::from_millis(50), 0.0, 0.0);
+
+        for _ in 0..100 {
+            assert!(policy.backoff(0) <= Duration::from_millis(10));
+            assert!(policy.backoff(2) <= Duration::from_millis(40));
+            assert!(policy.backoff(10) <= Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn budget_limits_retries_to_a_share_of_batches() {
+        let policy = RetryPolicy::new(5, Duration::ZERO, Duration::ZERO, 0.5, 2.0);
+
+        assert!(policy.withdraw());
+        assert!(policy.withdraw());
+        assert!(!policy.withdraw());
+
+        // Two batches earn one retry
+        policy.deposit();
+        assert!(!policy.withdraw());
+        policy.deposit();
+        assert!(policy.withdraw());
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{Notify, watch};
+use tokio::time::Instant;
+
+/// Coordinates a graceful shutdown between the signal handler, the sender, the batcher and
+/// its flush tasks.
+///
+/// Once triggered, the sender turns new requests away with `ProxyError::ServiceShutdown`, the
+/// batcher flushes whatever it holds without waiting for batches to fill, and the caller of
+/// [`Shutdown::flushes_done`] learns when the last flush has answered its waiters.
+pub struct Shutdown {
+    /// When the shutdown was triggered, if it was.
+    triggered: watch::Sender<Option<Instant>>,
+    flushes: AtomicUsize,
+    idle: Notify,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            triggered: watch::Sender::new(None),
+            flushes: AtomicUsize::new(0),
+            idle: Notify::new(),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Starts draining; later calls keep the time of the first.
+    pub fn trigger(&self) {
+        self.triggered.send_if_modified(|at| {
+            if at.is_some() {
+                return false;
+            }
+
+            *at = Some(Instant::now());
+            true
+        });
+    }
+
+    /// When the shutdown was triggered, if it was.
+    pub fn triggered_at(&self) -> Option<Instant> {
+        *self.triggered.borrow()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.triggered_at().is_some()
+    }
+
+    /// Waits until the shutdown is triggered.
+    pub async fn triggered(&self) {
+        let _ = self.triggered.subscribe().wait_for(Option::is_some).await;
+    }
+
+    /// Counts a running flush until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>) -> FlushGuard {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        FlushGuard(self.clone())
+    }
+
+    /// Waits until no flush is running.
+    pub async fn flushes_done(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+
+            if self.flushes.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// A flush counted by [`Shutdown::track`].
+pub struct FlushGuard(Arc<Shutdown>);
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        if self.0.flushes.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Resolves on SIGTERM or Ctrl-C.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut term = signal(SignalKind::terminate()).expect("SIGTERM handler");
+        tokio::select! {
+            _ = term.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn flushes_done_waits_for_the_last_flush() {
+        let shutdown = Arc::new(Shutdown::default());
+        assert!(!shutdown.is_draining());
+
+        let first = shutdown.track();
+        let second = shutdown.track();
+        shutdown.trigger();
+        assert!(shutdown.is_draining());
+        shutdown.triggered().await;
+
+        let done = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.flushes_done().await }
+        });
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!done.is_finished());
+
+        drop(second);
+        done.await.unwrap();
+    }
+}
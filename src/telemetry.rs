@@ -0,0 +1,143 @@
+use crate::config::AppConfig;
+use actix_web::dev::ServiceRequest;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Installs the log subscriber and, with `otlp_endpoint` set, exports spans over OTLP/HTTP.
+/// The returned provider must be shut down on exit to flush the last spans.
+pub fn init(cfg: &AppConfig) -> Option<SdkTracerProvider> {
+    let exporter = cfg.otlp_endpoint.as_deref().map(|endpoint| {
+        // The blocking exporter client must not be built on a runtime thread
+        let endpoint = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        std::thread::spawn(move || {
+            opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(endpoint)
+                .build()
+        })
+        .join()
+        .expect("OTLP exporter thread")
+    });
+    // Logged once the subscriber is installed
+    let (provider, error) = match exporter {
+        Some(Ok(exporter)) => (
+            Some(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(Resource::builder().with_service_name(cfg.service_name.clone()).build())
+                    .build(),
+            ),
+            None,
+        ),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+
+    let otel = provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer(env!("CARGO_PKG_NAME"))));
+    tracing_subscriber::registry()
+        .with(EnvFilter::new("info"))
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+
+    if let Some(e) = error {
+        tracing::warn!(error = %e, "OTLP export disabled");
+    }
+
+    provider
+}
+
+/// Span of one HTTP request, continuing the caller's trace if it sent a `traceparent`.
+pub fn request_span(req: &ServiceRequest) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        otel.kind = "server",
+        http.method = %req.method(),
+        http.path = req.path(),
+        http.status_code = tracing::field::Empty,
+    );
+
+    let parent = TraceContextPropagator::new().extract(&RequestHeaders(req.headers()));
+    if parent.span().span_context().is_valid() {
+        let _ = span.set_parent(parent);
+    }
+
+    span
+}
+
+/// Links `span` to `other`, e.g. a flush to the requests it carries.
+pub fn link(span: &Span, other: &Span) {
+    let cx = other.context();
+    let other = cx.span().span_context().clone();
+    if other.is_valid() {
+        span.add_link(other);
+    }
+}
+
+/// Adds the W3C `traceparent` of `span` to `headers`.
+pub fn inject(span: &Span, headers: &mut HeaderMap) {
+    TraceContextPropagator::new().inject_context(&span.context(), &mut RequestHeadersMut(headers));
+}
+
+struct RequestHeaders<'a>(&'a actix_web::http::header::HeaderMap);
+
+impl Extractor for RequestHeaders<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct RequestHeadersMut<'a>(&'a mut HeaderMap);
+
+impl Injector for RequestHeadersMut<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn traceparent_is_continued_and_passed_on() {
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let req = TestRequest::default()
+            .insert_header(("traceparent", format!("00-{trace_id}-00f067aa0ba902b7-01")))
+            .to_srv_request();
+        let request = request_span(&req);
+        let flush = tracing::info_span!("flush");
+        link(&flush, &request);
+
+        let mut headers = HeaderMap::new();
+        inject(&request, &mut headers);
+        let traceparent = headers.get("traceparent").unwrap().to_str().unwrap();
+        assert!(traceparent.starts_with(&format!("00-{trace_id}-")), "{traceparent}");
+        // A new span of the same trace, not the caller's
+        assert!(!traceparent.contains("00f067aa0ba902b7"), "{traceparent}");
+    }
+}
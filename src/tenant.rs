@@ -0,0 +1,125 @@
+use crate::config::AppConfig;
+use crate::error::ProxyError;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Identifies who a request belongs to: the `X-Tenant` header, or else the API key
+/// (`Authorization: Bearer <key>`). Requests carrying neither share the anonymous tenant.
+pub type TenantId = Option<Arc<str>>;
+
+/// Per-tenant weights and queue depth limits, plus the current depth of every tenant.
+///
+/// Weights set each tenant's share of batch capacity when several compete for it; depth
+/// limits cap how many of a tenant's items may be queued or in flight at once, so a single
+/// noisy client cannot take the whole queue.
+#[derive(Default)]
+pub struct Tenants {
+    weights: HashMap<String, u32>,
+    /// Depth limit for tenants without an entry in `max_queued`; `0` means unlimited.
+    default_max_queued: usize,
+    max_queued: HashMap<String, usize>,
+    depth: Mutex<HashMap<TenantId, usize>>,
+}
+
+impl Tenants {
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        Self {
+            weights: cfg.tenant_weights.clone(),
+            default_max_queued: cfg.tenant_max_queued,
+            max_queued: cfg.tenant_queue_limits.clone(),
+            depth: Mutex::default(),
+        }
+    }
+
+    /// Scheduling weight of `tenant`, `1` unless configured.
+    pub fn weight(&self, tenant: &TenantId) -> u32 {
+        tenant
+            .as_deref()
+            .and_then(|t| self.weights.get(t))
+            .copied()
+            .unwrap_or(1)
+            .max(1)
+    }
+
+    fn max_queued(&self, tenant: &TenantId) -> usize {
+        tenant
+            .as_deref()
+            .and_then(|t| self.max_queued.get(t))
+            .copied()
+            .unwrap_or(self.default_max_queued)
+    }
+
+    /// Reserves room for `n` items of `tenant`. The reservation is released when the returned
+    /// guard is dropped, i.e. once the request has its answer or gives up. A request of more
+    /// items than the tenant may ever have queued is invalid, as retrying it cannot succeed.
+    pub fn admit(self: &Arc<Self>, tenant: &TenantId, n: usize) -> Result<TenantPermit, ProxyError> {
+        let limit = self.max_queued(tenant);
+        if limit > 0 && n > limit {
+            return Err(ProxyError::InvalidRequest(format!(
+                "{n} inputs exceed the tenant's queue limit of {limit}"
+            )));
+        }
+
+        let mut depth = self.depth.lock().expect("tenant depth lock");
+        let current = depth.entry(tenant.clone()).or_default();
+
+        if limit > 0 && *current + n > limit {
+            // The tenant may be an API key, so it is not logged
+            tracing::warn!(depth = *current, limit, "tenant queue limit reached");
+            return Err(ProxyError::QueueFull { retry_after: 1 });
+        }
+        *current += n;
+
+        Ok(TenantPermit {
+            tenants: self.clone(),
+            tenant: tenant.clone(),
+            n,
+        })
+    }
+}
+
+/// Room reserved by [`Tenants::admit`].
+pub struct TenantPermit {
+    tenants: Arc<Tenants>,
+    tenant: TenantId,
+    n: usize,
+}
+
+impl Drop for TenantPermit {
+    fn drop(&mut self) {
+        let mut depth = self.tenants.depth.lock().expect("tenant depth lock");
+        if let Some(current) = depth.get_mut(&self.tenant) {
+            *current = current.saturating_sub(self.n);
+            if *current == 0 {
+                depth.remove(&self.tenant);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admit_enforces_per_tenant_depth_until_permits_drop() {
+        let tenants = Arc::new(Tenants {
+            default_max_queued: 3,
+            max_queued: HashMap::from([("indexer".to_string(), 1)]),
+            ..Default::default()
+        });
+        let indexer: TenantId = Some("indexer".into());
+        let search: TenantId = Some("search".into());
+
+        let first = tenants.admit(&indexer, 1).expect("within limit");
+        assert!(matches!(tenants.admit(&indexer, 1), Err(ProxyError::QueueFull { .. })));
+        // Other tenants are not affected by the indexer's backlog
+        let _search = tenants.admit(&search, 3).expect("within default limit");
+        assert!(tenants.admit(&search, 1).is_err());
+
+        drop(first);
+        assert!(tenants.admit(&indexer, 1).is_ok());
+        // More than the limit at once can never be admitted, so it is not worth a retry
+        assert!(matches!(tenants.admit(&search, 4), Err(ProxyError::InvalidRequest(_))));
+    }
+}
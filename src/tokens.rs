@@ -0,0 +1,91 @@
+use crate::config::AppConfig;
+use std::sync::Arc;
+
+/// Estimates how many tokens an input occupies upstream, so batches can be
+/// closed on a token budget instead of only on an item count.
+pub trait TokenEstimator: Send + Sync {
+    fn estimate(&self, input: &str) -> usize;
+}
+
+/// Character-count heuristic (~4 characters per token for English text with
+/// BPE/WordPiece tokenizers). Cheap and good enough to keep batches under budget.
+pub struct CharEstimator {
+    chars_per_token: usize,
+}
+
+impl Default for CharEstimator {
+    fn default() -> Self {
+        Self { chars_per_token: 4 }
+    }
+}
+
+impl TokenEstimator for CharEstimator {
+    fn estimate(&self, input: &str) -> usize {
+        input.chars().count().div_ceil(self.chars_per_token).max(1)
+    }
+}
+
+/// Exact counts from a Hugging Face `tokenizer.json`, usually the one of the model TEI serves.
+#[cfg(feature = "tokenizer")]
+pub struct HfEstimator {
+    tokenizer: tokenizers::Tokenizer,
+}
+
+#[cfg(feature = "tokenizer")]
+impl HfEstimator {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let tokenizer = tokenizers::Tokenizer::from_file(path).map_err(|e| e.to_string())?;
+
+        Ok(Self { tokenizer })
+    }
+}
+
+#[cfg(feature = "tokenizer")]
+impl TokenEstimator for HfEstimator {
+    fn estimate(&self, input: &str) -> usize {
+        match self.tokenizer.encode(input, true) {
+            Ok(encoding) => encoding.len(),
+            Err(_) => CharEstimator::default().estimate(input),
+        }
+    }
+}
+
+/// Builds the estimator selected by the configuration, falling back to the
+/// character heuristic when no tokenizer is configured or it cannot be loaded.
+pub fn estimator(cfg: &AppConfig) -> Arc<dyn TokenEstimator> {
+    let Some(path) = cfg.tokenizer_path.as_deref() else {
+        return Arc::new(CharEstimator::default());
+    };
+
+    #[cfg(feature = "tokenizer")]
+    match HfEstimator::from_file(path) {
+        Ok(est) => {
+            tracing::info!(path, "using tokenizer for token estimates");
+            return Arc::new(est);
+        }
+        Err(e) => tracing::warn!(path, error = %e, "failed to load tokenizer, using character heuristic"),
+    }
+
+    #[cfg(not(feature = "tokenizer"))]
+    tracing::warn!(
+        path,
+        "TOKENIZER_PATH set but built without the `tokenizer` feature, using character heuristic"
+    );
+
+    Arc::new(CharEstimator::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn char_estimator_rounds_up_and_never_returns_zero() {
+        let est = CharEstimator::default();
+
+        assert_eq!(est.estimate(""), 1);
+        assert_eq!(est.estimate("abcd"), 1);
+        assert_eq!(est.estimate("abcde"), 2);
+        assert_eq!(est.estimate(&"x".repeat(400)), 100);
+    }
+}
@@ -0,0 +1,529 @@
+use crate::config::AppConfig;
+use crate::error::ProxyError;
+use crate::limiter::{ConcurrencyLimiter, ConcurrencyLimits, Permit};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// How `send_batch` spreads batches over the TEI replicas.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    /// Each replica in turn.
+    #[default]
+    RoundRobin,
+    /// The replica with the fewest batches assigned and not yet answered.
+    LeastOutstanding,
+    /// The cheaper of two random replicas, by observed latency weighted by outstanding batches.
+    #[serde(rename = "p2c")]
+    PowerOfTwo,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-outstanding" => Ok(Balance::LeastOutstanding),
+            "p2c" => Ok(Balance::PowerOfTwo),
+            other => Err(format!(
+                "unknown balance strategy `{other}` (expected `round-robin`, `least-outstanding` or `p2c`)"
+            )),
+        }
+    }
+}
+
+/// Whether a replica receives batches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    /// Failed `eject_after` times in a row; gets no batches until a probe succeeds.
+    Ejected,
+    /// Back in rotation after a successful probe, but ejected again on the first failure.
+    HalfOpen,
+}
+
+/// When replicas are ejected and reinstated.
+#[derive(Clone, Copy, Debug)]
+pub struct HealthPolicy {
+    /// Consecutive failures, of batches or probes, after which a replica is ejected.
+    pub eject_after: u32,
+    /// Consecutive successes a half-open replica needs to count as healthy again.
+    pub reinstate_after: u32,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self {
+            eject_after: 3,
+            reinstate_after: 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HealthState {
+    health: Health,
+    /// Consecutive failures while healthy, consecutive successes while half-open.
+    streak: u32,
+}
+
+/// One TEI instance.
+pub struct Replica {
+    pub url: String,
+    /// Limits concurrent batches sent to this replica.
+    pub limiter: Arc<ConcurrencyLimiter>,
+    /// Batches leased to this replica and not yet answered.
+    outstanding: AtomicUsize,
+    /// Moving average of successful call latency in microseconds; `0` until the first call.
+    latency_us: AtomicU64,
+    health: Mutex<HealthState>,
+    /// Last successful batch or probe.
+    answered_at: Mutex<Option<Instant>>,
+}
+
+impl Replica {
+    fn new(url: &str, limits: ConcurrencyLimits) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            limiter: Arc::new(ConcurrencyLimiter::new(limits)),
+            outstanding: AtomicUsize::new(0),
+            latency_us: AtomicU64::new(0),
+            health: Mutex::new(HealthState {
+                health: Health::Healthy,
+                streak: 0,
+            }),
+            answered_at: Mutex::new(None),
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        self.health.lock().expect("health lock").health
+    }
+
+    /// Tracks the outcome of a batch or probe, ejecting or reinstating the replica as needed.
+    fn record(&self, ok: bool, policy: &HealthPolicy) {
+        if ok {
+            *self.answered_at.lock().expect("answered lock") = Some(Instant::now());
+        }
+
+        let mut state = self.health.lock().expect("health lock");
+        let before = state.health;
+
+        match (state.health, ok) {
+            (Health::Healthy, true) => state.streak = 0,
+            (Health::Healthy, false) => {
+                state.streak += 1;
+                if state.streak >= policy.eject_after {
+                    state.health = Health::Ejected;
+                }
+            }
+            (Health::Ejected, true) => {
+                state.health = Health::HalfOpen;
+                state.streak = 0;
+            }
+            (Health::Ejected, false) => {}
+            (Health::HalfOpen, true) => state.streak += 1,
+            (Health::HalfOpen, false) => state.health = Health::Ejected,
+        }
+        if state.health == Health::HalfOpen && state.streak >= policy.reinstate_after {
+            state.health = Health::Healthy;
+            state.streak = 0;
+        }
+
+        if state.health != before {
+            match state.health {
+                Health::Ejected => tracing::warn!(upstream = %self.url, "upstream ejected"),
+                Health::HalfOpen => tracing::info!(upstream = %self.url, "upstream half-open"),
+                Health::Healthy => tracing::info!(upstream = %self.url, "upstream reinstated"),
+            }
+        }
+    }
+
+    fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    /// Expected latency of one more batch, used by `Balance::PowerOfTwo`.
+    fn cost(&self) -> u64 {
+        self.latency_us.load(Ordering::Relaxed) * (self.outstanding() as u64 + 1)
+    }
+}
+
+/// The TEI replicas batches are sent to.
+///
+/// Replicas that keep failing, by the outcome of their batches or of the periodic `/health`
+/// probes, are ejected and get no more batches until a probe succeeds, after which they are
+/// half-open until they have succeeded `reinstate_after` times. Should every replica be ejected,
+/// batches are spread over all of them again rather than failed outright.
+///
+/// A batch waits for *any* replica in rotation to have room, so a slow or hung replica only
+/// holds up the batches it already has.
+pub struct Upstreams {
+    replicas: Vec<Arc<Replica>>,
+    balance: Balance,
+    policy: HealthPolicy,
+    next: AtomicUsize,
+    /// Woken whenever a lease is released or the replicas are closed.
+    freed: Arc<Notify>,
+}
+
+impl Upstreams {
+    /// Every replica gets a concurrency limiter of its own.
+    pub fn new(urls: &[String], limits: ConcurrencyLimits, balance: Balance) -> Self {
+        assert!(!urls.is_empty(), "at least one upstream URL is required");
+
+        Self {
+            replicas: urls.iter().map(|url| Arc::new(Replica::new(url, limits))).collect(),
+            balance,
+            policy: HealthPolicy::default(),
+            next: AtomicUsize::new(0),
+            freed: Arc::default(),
+        }
+    }
+
+    pub fn from_config(cfg: &AppConfig) -> Self {
+        let limits = if cfg.adaptive_concurrency {
+            ConcurrencyLimits {
+                initial: cfg.batch_concurrency,
+                min: cfg.min_batch_concurrency.max(1),
+                max: cfg.max_batch_concurrency.max(cfg.min_batch_concurrency).max(1),
+            }
+        } else {
+            ConcurrencyLimits::fixed(cfg.batch_concurrency)
+        };
+
+        Self::new(&cfg.tei_urls, limits, cfg.balance).with_health_policy(HealthPolicy {
+            eject_after: cfg.eject_after_failures,
+            reinstate_after: cfg.reinstate_after_successes,
+        })
+    }
+
+    pub fn with_health_policy(mut self, policy: HealthPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Probes every replica's `/health` each `interval`; a probe that fails or takes longer
+    /// than `timeout` counts as a failure. The probes stop once `self` is dropped.
+    pub fn spawn_health_checks(self: &Arc<Self>, interval: Duration, timeout: Duration) {
+        let client = Client::builder().timeout(timeout).build().expect("reqwest client");
+
+        for idx in 0..self.replicas.len() {
+            let upstreams = Arc::downgrade(self);
+            let client = client.clone();
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                loop {
+                    ticker.tick().await;
+                    let Some((replica, policy)) = probe_target(&upstreams, idx) else {
+                        return;
+                    };
+
+                    let resp = client.get(format!("{}/health", replica.url)).send().await;
+                    let ok = resp.is_ok_and(|r| r.status().is_success());
+                    replica.record(ok, &policy);
+                }
+            });
+        }
+    }
+
+    pub fn replicas(&self) -> &[Arc<Replica>] {
+        &self.replicas
+    }
+
+    /// Whether some replica answered a batch or probe within `max_age`.
+    pub fn answered_within(&self, max_age: Duration) -> bool {
+        let now = Instant::now();
+        self.replicas.iter().any(|r| {
+            r.answered_at
+                .lock()
+                .expect("answered lock")
+                .is_some_and(|at| now.duration_since(at) <= max_age)
+        })
+    }
+
+    /// Average observed latency of a batch across the replicas in rotation, once any has answered.
+    pub fn service_time(&self) -> Option<Duration> {
+        let latencies: Vec<u64> = self
+            .replicas
+            .iter()
+            .filter(|r| r.health() != Health::Ejected)
+            .map(|r| r.latency_us.load(Ordering::Relaxed))
+            .filter(|&us| us > 0)
+            .collect();
+        if latencies.is_empty() {
+            return None;
+        }
+
+        Some(Duration::from_micros(
+            latencies.iter().sum::<u64>() / latencies.len() as u64,
+        ))
+    }
+
+    /// Waits until some replica in rotation admits one more batch and leases it. Replicas are
+    /// tried in the order `balance` prefers; those without room are passed over.
+    pub async fn acquire(&self) -> Result<Lease, ProxyError> {
+        self.when_free(|| {
+            for replica in self.ranked() {
+                if let Some(permit) = replica.limiter.try_acquire()? {
+                    return Ok(Some(Lease {
+                        outstanding: Outstanding::new(replica),
+                        policy: self.policy,
+                        permit,
+                        _freed: Freed(self.freed.clone()),
+                    }));
+                }
+            }
+            Ok(None)
+        })
+        .await
+    }
+
+    /// Waits until some replica in rotation could take one more batch, without leasing it.
+    pub async fn ready(&self) -> Result<(), ProxyError> {
+        self.when_free(|| {
+            for replica in self.eligible() {
+                if replica.limiter.has_room()? {
+                    return Ok(Some(()));
+                }
+            }
+            Ok(None)
+        })
+        .await
+    }
+
+    /// Stops admitting batches; flushes still waiting for a replica fail with `ProxyError::ServiceShutdown`.
+    pub fn close(&self) {
+        for replica in &self.replicas {
+            replica.limiter.close();
+        }
+        self.freed.notify_waiters();
+    }
+
+    /// Runs `attempt` until it returns something, again every time a lease is released.
+    async fn when_free<T>(&self, attempt: impl Fn() -> Result<Option<T>, ProxyError>) -> Result<T, ProxyError> {
+        loop {
+            let freed = self.freed.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+
+            if let Some(found) = attempt()? {
+                return Ok(found);
+            }
+
+            freed.await;
+        }
+    }
+
+    /// Replicas in rotation, or all of them if every one is ejected.
+    fn eligible(&self) -> Vec<&Arc<Replica>> {
+        let eligible: Vec<_> = self.replicas.iter().filter(|r| r.health() != Health::Ejected).collect();
+        if eligible.is_empty() {
+            return self.replicas.iter().collect();
+        }
+
+        eligible
+    }
+
+    /// Eligible replicas, the one `balance` picks for the next batch first.
+    fn ranked(&self) -> Vec<Arc<Replica>> {
+        let mut eligible = self.eligible();
+        let n = eligible.len();
+        match self.balance {
+            Balance::RoundRobin => eligible.rotate_left(self.next.fetch_add(1, Ordering::Relaxed) % n),
+            Balance::LeastOutstanding => {
+                // Rotate the starting point so ties do not always go to the first replica
+                eligible.rotate_left(self.next.fetch_add(1, Ordering::Relaxed) % n);
+                eligible.sort_by_key(|r| r.outstanding());
+            }
+            Balance::PowerOfTwo if n == 1 => {}
+            Balance::PowerOfTwo => {
+                let a = fastrand::usize(..n);
+                let b = (a + 1 + fastrand::usize(..n - 1)) % n;
+                let (first, second) = if eligible[b].cost() < eligible[a].cost() {
+                    (b, a)
+                } else {
+                    (a, b)
+                };
+                let (first, second) = (eligible[first], eligible[second]);
+                eligible.retain(|r| !Arc::ptr_eq(r, first) && !Arc::ptr_eq(r, second));
+                eligible.splice(0..0, [first, second]);
+            }
+        }
+
+        eligible.into_iter().cloned().collect()
+    }
+}
+
+/// Wakes the waiters of [`Upstreams`] once dropped.
+struct Freed(Arc<Notify>);
+
+impl Drop for Freed {
+    fn drop(&mut self) {
+        self.0.notify_waiters();
+    }
+}
+
+fn probe_target(upstreams: &Weak<Upstreams>, idx: usize) -> Option<(Arc<Replica>, HealthPolicy)> {
+    let upstreams = upstreams.upgrade()?;
+
+    Some((upstreams.replicas[idx].clone(), upstreams.policy))
+}
+
+/// Counts a batch against its replica until dropped.
+struct Outstanding(Arc<Replica>);
+
+impl Outstanding {
+    fn new(replica: Arc<Replica>) -> Self {
+        replica.outstanding.fetch_add(1, Ordering::Relaxed);
+        Self(replica)
+    }
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A replica picked for one batch, holding one of its permits until dropped.
+pub struct Lease {
+    outstanding: Outstanding,
+    policy: HealthPolicy,
+    permit: Permit,
+    /// Dropped after `permit`, so woken waiters find it released.
+    _freed: Freed,
+}
+
+impl Lease {
+    pub fn url(&self) -> &str {
+        &self.outstanding.0.url
+    }
+
+    /// Current concurrency limit of the replica.
+    pub fn limit(&self) -> usize {
+        self.outstanding.0.limiter.limit()
+    }
+
+    /// Records a successful call and its latency.
+    pub fn observe(&self, latency: Duration) {
+        let replica = &self.outstanding.0;
+        replica.record(true, &self.policy);
+        self.permit.succeeded(latency);
+
+        let sample = latency.as_micros() as u64;
+        let _ = replica
+            .latency_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| match avg {
+                0 => Some(sample),
+                avg => Some((avg * 7 + sample) / 8),
+            });
+    }
+
+    /// Records a call that failed for reasons of the replica, see [`ProxyError::is_upstream_failure`].
+    pub fn fail(&self) {
+        self.outstanding.0.record(false, &self.policy);
+        self.permit.failed();
+    }
+
+    /// Records a call the replica turned away because it is overloaded (`429`). Only the
+    /// concurrency limit backs off; the replica is not unhealthy.
+    pub fn throttled(&self) {
+        self.permit.failed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams(n: usize, balance: Balance) -> Upstreams {
+        let urls: Vec<_> = (0..n).map(|i| format!("http://tei-{i}")).collect();
+        Upstreams::new(&urls, ConcurrencyLimits::fixed(2), balance)
+    }
+
+    #[tokio::test]
+    async fn round_robin_cycles_through_replicas() {
+        let up = upstreams(3, Balance::RoundRobin);
+        let mut urls = Vec::new();
+        for _ in 0..4 {
+            urls.push(up.acquire().await.unwrap().url().to_string());
+        }
+
+        assert_eq!(urls, ["http://tei-0", "http://tei-1", "http://tei-2", "http://tei-0"]);
+    }
+
+    #[tokio::test]
+    async fn least_outstanding_avoids_busy_replicas() {
+        let up = upstreams(2, Balance::LeastOutstanding);
+        let first = up.acquire().await.unwrap();
+        let second = up.acquire().await.unwrap();
+        assert_ne!(first.url(), second.url());
+
+        drop(first);
+        let third = up.acquire().await.unwrap();
+        assert_ne!(third.url(), second.url());
+    }
+
+    #[tokio::test]
+    async fn power_of_two_prefers_the_faster_replica() {
+        let up = upstreams(2, Balance::PowerOfTwo);
+        up.replicas()[0].latency_us.store(50_000, Ordering::Relaxed);
+        up.replicas()[1].latency_us.store(5_000, Ordering::Relaxed);
+
+        for _ in 0..10 {
+            assert_eq!(up.acquire().await.unwrap().url(), "http://tei-1");
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_replica_is_ejected_and_reinstated_through_half_open() {
+        let up = upstreams(2, Balance::RoundRobin);
+        let policy = HealthPolicy::default();
+        let bad = up.replicas()[0].clone();
+
+        for _ in 0..policy.eject_after {
+            bad.record(false, &policy);
+        }
+        assert_eq!(bad.health(), Health::Ejected);
+        for _ in 0..4 {
+            assert_eq!(up.acquire().await.unwrap().url(), "http://tei-1");
+        }
+
+        // A successful probe lets it back in, but one more failure ejects it again
+        bad.record(true, &policy);
+        assert_eq!(bad.health(), Health::HalfOpen);
+        bad.record(false, &policy);
+        assert_eq!(bad.health(), Health::Ejected);
+
+        for _ in 0..=policy.reinstate_after {
+            bad.record(true, &policy);
+        }
+        assert_eq!(bad.health(), Health::Healthy);
+    }
+
+    #[tokio::test]
+    async fn probes_eject_unreachable_replicas() {
+        let urls = ["http://127.0.0.1:12345".to_string()];
+        let up = Arc::new(
+            Upstreams::new(&urls, ConcurrencyLimits::fixed(1), Balance::RoundRobin).with_health_policy(HealthPolicy {
+                eject_after: 1,
+                reinstate_after: 1,
+            }),
+        );
+        up.spawn_health_checks(Duration::from_millis(10), Duration::from_millis(100));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(up.replicas()[0].health(), Health::Ejected);
+    }
+}
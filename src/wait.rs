@@ -0,0 +1,111 @@
+use crate::config::AppConfig;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Picks how long a batch waits to fill from the observed arrival rate and upstream service time.
+///
+/// Waiting only pays off while it brings in more items. When fewer than one is expected within
+/// `max_wait`, batches go out after `min_wait`. Otherwise a batch waits for the time it needs to
+/// fill up, but at most half the upstream service time: items arriving while a batch is upstream
+/// make the next batch anyway, so holding them any longer only adds latency.
+pub struct AdaptiveWait {
+    min_wait: Duration,
+    max_wait: Duration,
+    /// Moving average of the time between arrivals; `None` until the second one.
+    gap: Option<Duration>,
+    last_arrival: Option<Instant>,
+}
+
+impl AdaptiveWait {
+    pub fn new(min_wait: Duration, max_wait: Duration) -> Self {
+        Self {
+            min_wait: min_wait.min(max_wait),
+            max_wait,
+            gap: None,
+            last_arrival: None,
+        }
+    }
+
+    /// Builds the estimator described by `cfg`, or `None` when the wait is fixed.
+    pub fn from_config(cfg: &AppConfig) -> Option<Self> {
+        if !cfg.adaptive_wait {
+            return None;
+        }
+
+        Some(Self::new(
+            Duration::from_millis(cfg.min_wait_time_ms),
+            Duration::from_millis(cfg.max_wait_time_ms),
+        ))
+    }
+
+    /// Records an item picked up by the batcher at `now`.
+    pub fn arrived(&mut self, now: Instant) {
+        if let Some(last) = self.last_arrival.replace(now) {
+            // Any gap past the max wait means the same (no batching gain); capping it lets the
+            // average catch up within a few items when a burst follows an idle period.
+            let sample = now.duration_since(last).min(self.max_wait * 2);
+            self.gap = Some(match self.gap {
+                Some(gap) => (gap * 7 + sample) / 8,
+                None => sample,
+            });
+        }
+    }
+
+    /// Wait window for a batch that already holds `queued` of `max_batch_size` items, given the
+    /// upstream's average `service_time` if known.
+    pub fn wait(&self, queued: usize, max_batch_size: usize, service_time: Option<Duration>) -> Duration {
+        let Some(gap) = self.gap else {
+            return self.min_wait;
+        };
+        if gap > self.max_wait {
+            return self.min_wait;
+        }
+
+        let missing = max_batch_size.saturating_sub(queued) as u32;
+        let fill = gap.saturating_mul(missing);
+        let cap = service_time.map_or(self.max_wait, |s| s / 2);
+
+        fill.min(cap).clamp(self.min_wait, self.max_wait)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_arrivals(every: Duration, n: usize) -> AdaptiveWait {
+        let mut wait = AdaptiveWait::new(Duration::from_millis(1), Duration::from_millis(20));
+        let mut now = Instant::now();
+        for _ in 0..n {
+            wait.arrived(now);
+            now += every;
+        }
+
+        wait
+    }
+
+    #[test]
+    fn sparse_traffic_gets_the_min_wait() {
+        let wait = with_arrivals(Duration::from_millis(100), 10);
+        assert_eq!(wait.wait(1, 32, None), Duration::from_millis(1));
+
+        // Nothing to go by yet
+        let wait = with_arrivals(Duration::ZERO, 1);
+        assert_eq!(wait.wait(1, 32, None), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn busy_traffic_waits_to_fill_up_to_half_the_service_time() {
+        let wait = with_arrivals(Duration::from_millis(2), 10);
+
+        // 4 more items at one every 2ms
+        assert_eq!(wait.wait(28, 32, None), Duration::from_millis(8));
+        // Filling the whole batch would take 62ms, beyond the max wait
+        assert_eq!(wait.wait(1, 32, None), Duration::from_millis(20));
+        // A fast upstream does not make it worth waiting that long
+        assert_eq!(
+            wait.wait(1, 32, Some(Duration::from_millis(10))),
+            Duration::from_millis(5)
+        );
+    }
+}